@@ -25,7 +25,7 @@ exec(char *path, char **argv)
 {
   char *s, *last;
   int i, off;
-  uint64 argc, sz = 0, sp, ustack[MAXARG], stackbase;
+  uint64 argc, sz = 0, sp, ustack[MAXARG], stackbase, stacklimit;
   struct elfhdr elf;
   struct inode *ip;
   struct proghdr ph;
@@ -113,15 +113,19 @@ exec(char *path, char **argv)
   p = myproc();
   uint64 oldsz = p->sz;
 
-  // Allocate two pages at the next page boundary.
-  // Make the first inaccessible as a stack guard.
-  // Use the second as the user stack.
+  // Allocate a page at the next page boundary, and make it
+  // inaccessible as a stack guard. Above it, reserve MAXSTACK
+  // pages for the user stack, which grows down on demand.
+  // Only map its top page for now.
   sz = PGROUNDUP(sz);
   uint64 sz1;
-  if((sz1 = uvmalloc(pagetable, sz, sz + 2*PGSIZE, PTE_W)) == 0)
+  if((sz1 = uvmalloc(pagetable, sz, sz + PGSIZE, PTE_W)) == 0)
+    goto bad;
+  uvmclear(pagetable, sz);
+  stacklimit = sz1;
+  sz = stacklimit + MAXSTACK*PGSIZE;
+  if(uvmalloc(pagetable, sz - PGSIZE, sz, PTE_W) == 0)
     goto bad;
-  sz = sz1;
-  uvmclear(pagetable, sz-2*PGSIZE);
   sp = sz;
   stackbase = sp - PGSIZE;
   // Deallocate the first page, so that dereferencing a null pointer results in a page fault
@@ -161,11 +165,21 @@ exec(char *path, char **argv)
   safestrcpy(p->name, last, sizeof(p->name));
     
   // Commit to the user image.
+  munmap_all(p);
   oldpagetable = p->pagetable;
   p->pagetable = pagetable;
   p->sz = sz;
+  p->stackbottom = stackbase;
+  p->stacklimit = stacklimit;
+  // the program and the stack guard below stacklimit,
+  // and the top page of the stack.
+  acquire(&p->lock);
+  p->resident = stacklimit / PGSIZE + 1;
+  release(&p->lock);
+  memset(p->heapprots, 0, sizeof(p->heapprots));
   p->trapframe->epc = elf.entry;  // initial program counter = main
   p->trapframe->sp = sp; // initial stack pointer
+  proc_flush_tlb(p);
   proc_freepagetable(oldpagetable, oldsz);
 
   if (p->pid == 1) {
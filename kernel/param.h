@@ -3,14 +3,20 @@
 #define NPROC        64  // maximum number of processes
 #define NCPU          8  // maximum number of CPUs
 #define NOFILE       16  // open files per process
+#define NVMA         16  // memory mapped regions per process
+#define NHEAPPROT    16  // heap ranges with their own protection per process
+#define NSHM         16  // shared memory segments per system
 #define NFILE       100  // open files per system
 #define NINODE       50  // maximum number of active i-nodes
 #define NDEV         10  // maximum major device number
 #define ROOTDEV       1  // device number of file system root disk
 #define MAXARG       32  // max exec arguments
+#define MAXSTACK     64  // max pages of user stack
 #define MAXOPBLOCKS  10  // max # of blocks any FS op writes
 #define LOGSIZE      (MAXOPBLOCKS*3)  // max data blocks in on-disk log
 #define NBUF         (MAXOPBLOCKS*3)  // size of disk block cache
 #define FSSIZE       2000  // size of file system in blocks
 #define MAXPATH      128   // maximum file path name
+#define NSWAP        2048  // pages that can be swapped out
+#define SWAPSIZE     (NSWAP*4)  // size of swap area after the file system in blocks
 #endif // PARAM_H
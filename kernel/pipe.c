@@ -74,6 +74,21 @@ pipeclose(struct pipe *pi, int writable)
     release(&pi->lock);
 }
 
+// copyin() and copyout() can't read a user page in from swap
+// or a mapped file while pi->lock is held, as that sleeps.
+// bring in the page at addr without the lock instead.
+// returns whether it was, so the copy can be tried again.
+static int
+pipefaultin(struct pipe *pi, uint64 addr)
+{
+  int ok;
+
+  release(&pi->lock);
+  ok = uvmswapin(myproc()->pagetable, addr, 1) == 0;
+  acquire(&pi->lock);
+  return ok;
+}
+
 int
 pipewrite(struct pipe *pi, uint64 addr, int n)
 {
@@ -91,8 +106,11 @@ pipewrite(struct pipe *pi, uint64 addr, int n)
       sleep(&pi->nwrite, &pi->lock);
     } else {
       char ch;
-      if(copyin(pr->pagetable, &ch, addr + i, 1) == -1)
+      if(copyin(pr->pagetable, &ch, addr + i, 1) == -1){
+        if(pipefaultin(pi, addr + i))
+          continue;
         break;
+      }
       pi->data[pi->nwrite++ % PIPESIZE] = ch;
       i++;
     }
@@ -106,24 +124,30 @@ pipewrite(struct pipe *pi, uint64 addr, int n)
 int
 piperead(struct pipe *pi, uint64 addr, int n)
 {
-  int i;
+  int i = 0;
   struct proc *pr = myproc();
   char ch;
 
   acquire(&pi->lock);
-  while(pi->nread == pi->nwrite && pi->writeopen){  //DOC: pipe-empty
-    if(killed(pr)){
-      release(&pi->lock);
-      return -1;
+  while(i < n){
+    while(i == 0 && pi->nread == pi->nwrite && pi->writeopen){  //DOC: pipe-empty
+      if(killed(pr)){
+        release(&pi->lock);
+        return -1;
+      }
+      sleep(&pi->nread, &pi->lock); //DOC: piperead-sleep
     }
-    sleep(&pi->nread, &pi->lock); //DOC: piperead-sleep
-  }
-  for(i = 0; i < n; i++){  //DOC: piperead-copy
     if(pi->nread == pi->nwrite)
       break;
-    ch = pi->data[pi->nread++ % PIPESIZE];
-    if(copyout(pr->pagetable, addr + i, (const unsigned char*) &ch, 1) == -1)
+    // the byte stays in the pipe until it is copied.
+    ch = pi->data[pi->nread % PIPESIZE];  //DOC: piperead-copy
+    if(copyout(pr->pagetable, addr + i, (const unsigned char*) &ch, 1) == -1){
+      if(pipefaultin(pi, addr + i))
+        continue;
       break;
+    }
+    pi->nread++;
+    i++;
   }
   wakeup(&pi->nwrite);  //DOC: piperead-wakeup
   release(&pi->lock);
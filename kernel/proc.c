@@ -180,7 +180,14 @@ freeproc(struct proc *p)
   if(p->pagetable)
     proc_freepagetable(p->pagetable, p->sz);
   p->pagetable = 0;
+  p->resident = 0;
+  p->asid = 0;
+  p->tlbcpu = -1;
   p->sz = 0;
+  p->stackbottom = 0;
+  p->stacklimit = 0;
+  memset(p->heapprots, 0, sizeof(p->heapprots));
+  memset(&p->faults, 0, sizeof(p->faults));
   p->pid = 0;
   p->parent = 0;
   p->name[0] = 0;
@@ -267,6 +274,7 @@ userinit(void)
   // and data into it.
   uvmfirst(p->pagetable, initcode, sizeof(initcode));
   p->sz = PGSIZE;
+  p->resident = 1;
 
   // prepare for the very first "return" from kernel to user.
   p->trapframe->epc = 0;      // user program counter
@@ -280,6 +288,32 @@ userinit(void)
   release(&p->lock);
 }
 
+// The reclaimer's very first scheduling by scheduler()
+// will swtch here.
+static void
+reclaimret(void)
+{
+  // Still holding p->lock from scheduler.
+  release(&myproc()->lock);
+  reclaimer();
+}
+
+// Start the kernel thread that swaps pages out, and
+// kills processes, when memory runs low.
+void
+reclaiminit(void)
+{
+  struct proc *p;
+
+  if((p = allocproc()) == 0)
+    panic("reclaiminit");
+  p->context.ra = (uint64)reclaimret;
+  safestrcpy(p->name, "reclaim", sizeof(p->name));
+  p->state = RUNNABLE;
+
+  release(&p->lock);
+}
+
 // Grow or shrink user memory by n bytes.
 // Return 0 on success, -1 on failure.
 int
@@ -290,11 +324,33 @@ growproc(int n)
 
   sz = p->sz;
   if(n > 0){
-    if((sz = uvmalloc(p->pagetable, sz, sz + n, PTE_W)) == 0) {
+    // pages are allocated lazily, when first touched.
+    if(sz + n > USYSCALL)
+      return -1;
+    // the heap may not grow into memory mapped regions.
+    for(struct vma *v = p->vmas; v < &p->vmas[NVMA]; v++)
+      if(v->len && sz + n > v->addr)
+        return -1;
+    sz += n;
+  } else if(n < 0 && sz + n < sz){
+    // a megapage the new end falls in the middle of
+    // has to be split before part of it is freed.
+    if(uvmsplit(p, PGROUNDUP(sz + n)) < 0)
       return -1;
+    uint64 npages = (PGROUNDUP(sz) - PGROUNDUP(sz + n)) / PGSIZE;
+    uint64 unmapped = uvmunmap(p->pagetable, PGROUNDUP(sz + n), npages, 1);
+    acquire(&p->lock);
+    p->resident -= unmapped;
+    release(&p->lock);
+    sz += n;
+    proc_flush_tlb(p);
+    // memory the heap grows back over later is readable and writeable.
+    for(struct heapprot *h = p->heapprots; h < &p->heapprots[NHEAPPROT]; h++){
+      if(h->addr >= PGROUNDUP(sz))
+        h->len = 0;
+      else if(h->addr + h->len > PGROUNDUP(sz))
+        h->len = PGROUNDUP(sz) - h->addr;
     }
-  } else if(n < 0){
-    sz = uvmdealloc(p->pagetable, sz, sz + n);
   }
   p->sz = sz;
   return 0;
@@ -315,12 +371,28 @@ fork(void)
   }
 
   // Copy user memory from parent to child.
-  if(uvmcopy(p->pagetable, np->pagetable, p->sz) < 0){
+  // the parent's pages become copy-on-write, so their writeable
+  // translations are dropped, even if the copy fails part way.
+  int copied = uvmcopy(p->pagetable, np->pagetable, p->sz);
+  proc_flush_tlb(p);
+  if(copied < 0){
     freeproc(np);
     release(&np->lock);
     return -1;
   }
   np->sz = p->sz;
+  np->stackbottom = p->stackbottom;
+  np->stacklimit = p->stacklimit;
+  memmove(np->heapprots, p->heapprots, sizeof(p->heapprots));
+
+  // Copy memory mapped regions.
+  copied = mmap_copy(p, np);
+  proc_flush_tlb(p);
+  if(copied < 0){
+    freeproc(np);
+    release(&np->lock);
+    return -1;
+  }
   np->tracing_mask = p->tracing_mask;
 
   // copy saved user registers.
@@ -345,7 +417,13 @@ fork(void)
   np->parent = p;
   release(&wait_lock);
 
+  // the child has every page in memory the parent has.
+  acquire(&p->lock);
+  uint64 resident = p->resident;
+  release(&p->lock);
+
   acquire(&np->lock);
+  np->resident = resident;
   np->state = RUNNABLE;
   release(&np->lock);
 
@@ -378,6 +456,10 @@ exit(int status)
   if(p == initproc)
     panic("init exiting");
 
+  // Unmap memory mapped regions, writing back shared pages,
+  // while their files are still open.
+  munmap_all(p);
+
   // Close all open files.
   for(int fd = 0; fd < NOFILE; fd++){
     if(p->ofile[fd]){
@@ -418,7 +500,7 @@ int
 wait(uint64 addr)
 {
   struct proc *pp;
-  int havekids, pid;
+  int havekids, pid, retry;
   struct proc *p = myproc();
 
   acquire(&wait_lock);
@@ -426,6 +508,7 @@ wait(uint64 addr)
   for(;;){
     // Scan through table looking for exited children.
     havekids = 0;
+    retry = 0;
     for(pp = proc; pp < &proc[NPROC]; pp++){
       if(pp->parent == p){
         // make sure the child isn't still in exit() or swtch().
@@ -439,7 +522,14 @@ wait(uint64 addr)
                                   sizeof(pp->xstate)) < 0) {
             release(&pp->lock);
             release(&wait_lock);
-            return -1;
+            // swapping addr in, or reading it from a mapped file,
+            // sleeps, so it can't be done holding the locks.
+            // the children are then scanned again.
+            if(uvmswapin(p->pagetable, addr, sizeof(pp->xstate)) < 0)
+              return -1;
+            acquire(&wait_lock);
+            retry = 1;
+            break;
           }
           freeproc(pp);
           release(&pp->lock);
@@ -449,6 +539,8 @@ wait(uint64 addr)
         release(&pp->lock);
       }
     }
+    if(retry)
+      continue;
 
     // No point waiting if we don't have any children.
     if(!havekids || killed(p)){
@@ -479,22 +571,32 @@ scheduler(void)
     // Avoid deadlock by ensuring that devices can interrupt.
     intr_on();
 
+    int found = 0;
     for(p = proc; p < &proc[NPROC]; p++) {
       acquire(&p->lock);
-      if(p->state == RUNNABLE) {
+      if(p->state == RUNNABLE && !p->pageout) {
         // Switch to chosen process.  It is the process's job
         // to release its lock and then reacquire it
         // before jumping back to us.
         p->state = RUNNING;
         c->proc = p;
+        // the TLB entries of its ASID on this hart are stale if
+        // the process last returned to user space on another one.
+        if(p->tlbcpu != cpuid())
+          p->tlbcpu = -1;
         swtch(&c->context, &p->context);
 
         // Process is done running for now.
         // It should have changed its p->state before coming back.
         c->proc = 0;
+        found = 1;
       }
       release(&p->lock);
     }
+    if(found == 0){
+      // Nothing to run, so zero some free pages for later.
+      kzero_refill();
+    }
   }
 }
 
@@ -3,6 +3,7 @@
 #include "param.h"
 #include "spinlock.h"
 #include "defs.h"
+#include "sysinfo.h"
 
 // Saved registers for kernel context switches.
 struct context {
@@ -30,6 +31,9 @@ struct cpu {
   struct context context;     // swtch() here to enter scheduler().
   int noff;                   // Depth of push_off() nesting.
   int intena;                 // Were interrupts enabled before push_off()?
+  pagetable_t pagetable;      // Root page table: the kernel's, and user memory above MAXVA.
+  pagetable_t aliased;        // User page table aliased in it, for copyin() and copyout().
+  uint64 tablesfreed;         // User page tables freed when user memory was last aliased.
 };
 
 extern struct cpu cpus[NCPU];
@@ -85,6 +89,25 @@ struct trapframe {
   /* 280 */ uint64 t6;
 };
 
+// A region of memory mapped by mmap
+struct vma {
+  uint64 addr;                 // Start of the region
+  uint64 len;                  // Length in bytes, a multiple of PGSIZE. 0 if unused
+  int prot;                    // PROT_ flags from fcntl.h
+  int flags;                   // MAP_ flags from fcntl.h
+  struct file *file;           // Backing file, 0 for anonymous mappings
+  uint64 off;                  // Offset into file of addr
+  int shm;                     // Shared memory segment + 1, 0 if none
+};
+
+// Part of the heap given its own protection with mprotect,
+// which its pages get when they are faulted in
+struct heapprot {
+  uint64 addr;                 // Start of the range
+  uint64 len;                  // Length in bytes, a multiple of PGSIZE. 0 if unused
+  int prot;                    // PROT_ flags from fcntl.h
+};
+
 // Per-process state
 struct proc {
   struct spinlock lock;
@@ -95,6 +118,8 @@ struct proc {
   int killed;                  // If non-zero, have been killed
   int xstate;                  // Exit status to be returned to parent's wait
   int pid;                     // Process ID
+  int pageout;                 // If non-zero, pages are being swapped out, so it can't run
+  uint64 resident;             // User pages in memory, counted for the OOM killer
 
   // wait_lock must be held when using this:
   struct proc *parent;         // Parent process
@@ -102,8 +127,13 @@ struct proc {
   // these are private to the process, so p->lock need not be held.
   uint64 kstack;               // Virtual address of kernel stack
   uint64 sz;                   // Size of process memory (bytes)
+  uint64 stackbottom;          // Lowest mapped page of the user stack
+  uint64 stacklimit;           // Lowest address the user stack may grow down to
   int tracing_mask;            // Mask for System calls to be traced
   pagetable_t pagetable;       // User page table
+  uint64 asid;                 // ASID for the TLB, with its generation above it
+  int tlbcpu;                  // Hart whose TLB entries of the ASID are current, or -1
+  int userpreempt;             // If non-zero, preempted in user mode, so pages can be swapped out
   struct trapframe *trapframe; // data page for trampoline.S
   struct usyscall *usyscall;   // data page for user-mapped syscalls
   struct context context;      // swtch() here to run process
@@ -115,5 +145,10 @@ struct proc {
   struct trapframe alarm_trapframe;
   int in_alarm_handler;
   void (*alarm_handler)();     // handler to call when alarming
+  struct vma vmas[NVMA];       // Memory mapped regions
+  struct heapprot heapprots[NHEAPPROT]; // Heap ranges not readable and writeable
+  struct faultstats faults;    // Page faults of this process
 };
+
+extern struct proc proc[NPROC];
 #endif // PROC_H
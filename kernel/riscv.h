@@ -199,10 +199,28 @@ w_pmpaddr0(uint64 x)
   asm volatile("csrw pmpaddr0, %0" : : "r" (x));
 }
 
-// use riscv's sv39 page table scheme.
 #define SATP_SV39 (8L << 60)
+#define SATP_SV48 (9L << 60)
 
-#define MAKE_SATP(pagetable) (SATP_SV39 | (((uint64)pagetable) >> 12))
+#ifdef SV48
+// use riscv's sv48 page table scheme, with four levels.
+#define SATP_MODE SATP_SV48
+#define PAGING_LEVELS 4
+#else
+// use riscv's sv39 page table scheme, with three levels.
+#define SATP_MODE SATP_SV39
+#define PAGING_LEVELS 3
+#endif
+
+#define MAKE_SATP(pagetable) (SATP_MODE | (((uint64)pagetable) >> 12))
+
+// the ASID field of satp tags TLB entries with the address
+// space they belong to, so switching page tables only has
+// to flush the entries of the one switched to.
+#define SATP_ASID_SHIFT 44
+#define SATP_ASID_MASK  0xFFFFL
+
+#define MAKE_SATP_ASID(pagetable, asid) (MAKE_SATP(pagetable) | ((uint64)(asid) << SATP_ASID_SHIFT))
 
 // supervisor address translation and protection;
 // holds the address of the page table.
@@ -356,14 +374,14 @@ typedef uint64 *pagetable_t; // 512 PTEs
 
 #define PTE_FLAGS(pte) ((pte) & 0x3FF)
 
-// extract the three 9-bit page table indices from a virtual address.
+// extract the 9-bit page table indices from a virtual address.
 #define PXMASK          0x1FF // 9 bits
 #define PXSHIFT(level)  (PGSHIFT+(9*(level)))
 #define PX(level, va) ((((uint64) (va)) >> PXSHIFT(level)) & PXMASK)
 
 // one beyond the highest possible virtual address.
 // MAXVA is actually one bit less than the max allowed by
-// Sv39, to avoid having to sign-extend virtual addresses
-// that have the high bit set.
-#define MAXVA (1L << (9 + 9 + 9 + 12 - 1))
+// Sv39 or Sv48, to avoid having to sign-extend virtual
+// addresses that have the high bit set.
+#define MAXVA (1L << (9 * PAGING_LEVELS + 12 - 1))
 #endif // RISCV_H
@@ -10,7 +10,8 @@ typedef unsigned char uint8_t;
 typedef char int8_t;
 typedef void* uintptr_t;
 typedef int int32_t;
-typedef struct inode inode;";
+typedef struct inode inode;
+typedef struct proc proc_;";
 
 fn main() {
     let target = env::var("TARGET").unwrap();
@@ -57,6 +58,8 @@ fn bindgen(kernel_path: &Path) {
         .fold(bindgen::Builder::default(), |builder, kernel_header| {
             builder.header(kernel_header)
         })
+        // MAXVA and the paging mode have to match the C side, built with -DSV48
+        .clang_args(env::var_os("CARGO_FEATURE_SV48").map(|_| "-DSV48"))
         .use_core()
         .generate_cstr(true)
         .default_enum_style(bindgen::EnumVariation::Rust {
@@ -0,0 +1,177 @@
+use crate::printf::printf;
+use alloc::alloc::Layout;
+
+/// Number of live allocations the table can hold. Must be a power of two
+const TRACKED_ALLOCATIONS: usize = 1024;
+/// Number of call sites outstanding allocations can be grouped by. Must be a power of two
+const TRACKED_SITES: usize = 128;
+/// Address of a slot that has never held an allocation
+const EMPTY: usize = 0;
+/// Address of a slot whose allocation was freed, so lookups keep probing past it
+const TOMBSTONE: usize = usize::MAX;
+
+/// A live kernel allocation
+#[derive(Clone, Copy)]
+struct TrackedAllocation {
+    address: usize,
+    layout: Layout,
+    /// Bytes set aside by the allocator, including headers and rounding
+    size: usize,
+    /// Value of `TICKS` when the allocation was made
+    tick: u32,
+    /// Return address of the allocation site
+    caller: u64,
+}
+
+/// The outstanding allocations made from one call site
+#[derive(Clone, Copy)]
+struct CallSite {
+    caller: u64,
+    /// Number of allocations, 0 if this slot is unused
+    count: usize,
+    bytes: usize,
+    /// Earliest tick any of the allocations was made at
+    oldest: u32,
+}
+
+impl CallSite {
+    const UNUSED: CallSite = CallSite {
+        caller: 0,
+        count: 0,
+        bytes: 0,
+        oldest: u32::MAX,
+    };
+
+    fn add(&mut self, allocation: &TrackedAllocation) {
+        self.caller = allocation.caller;
+        self.count += 1;
+        self.bytes += allocation.size;
+        self.oldest = self.oldest.min(allocation.tick);
+    }
+}
+
+/// Outstanding kernel allocations, in an open-addressed hash table keyed by address
+pub(crate) struct AllocationTable {
+    slots: [TrackedAllocation; TRACKED_ALLOCATIONS],
+    /// Allocations that went unrecorded because the table was full
+    dropped: usize,
+    /// Allocations grouped by call site while dumping, in a hash table keyed by caller.
+    /// Kept here rather than on the stack, which has no room for it
+    sites: [CallSite; TRACKED_SITES],
+}
+
+impl AllocationTable {
+    // Only ever evaluated at compile time, to initialize the allocator's static
+    #[allow(clippy::large_stack_arrays)]
+    pub(crate) const fn new() -> Self {
+        Self {
+            slots: [TrackedAllocation {
+                address: EMPTY,
+                layout: Layout::new::<u8>(),
+                size: 0,
+                tick: 0,
+                caller: 0,
+            }; TRACKED_ALLOCATIONS],
+            dropped: 0,
+            sites: [CallSite::UNUSED; TRACKED_SITES],
+        }
+    }
+
+    /// Record a new allocation at `address`
+    pub(crate) fn insert(
+        &mut self,
+        address: usize,
+        layout: Layout,
+        size: usize,
+        caller: u64,
+        tick: u32,
+    ) {
+        let free_slot = probe(address >> 4, TRACKED_ALLOCATIONS).find(|slot| {
+            let slot_address = self.slots[*slot].address;
+            slot_address == EMPTY || slot_address == TOMBSTONE
+        });
+        match free_slot {
+            Some(slot) => {
+                self.slots[slot] = TrackedAllocation {
+                    address,
+                    layout,
+                    size,
+                    tick,
+                    caller,
+                };
+            }
+            None => self.dropped += 1,
+        }
+    }
+
+    /// Forget the allocation at `address`, if it was recorded
+    pub(crate) fn remove(&mut self, address: usize) {
+        if let Some(slot) = self.find(address) {
+            self.slots[slot].address = TOMBSTONE;
+        }
+    }
+
+    /// Update the layout of an allocation that was reallocated in place
+    pub(crate) fn resize(&mut self, address: usize, layout: Layout) {
+        if let Some(slot) = self.find(address) {
+            self.slots[slot].layout = layout;
+        }
+    }
+
+    /// Print the outstanding allocations, grouped by allocation site
+    // Callers are only hashed, so truncating them is harmless
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn dump(&mut self) {
+        self.sites = [CallSite::UNUSED; TRACKED_SITES];
+        // Allocations from more call sites than fit are reported together
+        let mut other_sites = CallSite::UNUSED;
+        for allocation in self
+            .slots
+            .iter()
+            .filter(|slot| slot.address != EMPTY && slot.address != TOMBSTONE)
+        {
+            let site = probe(allocation.caller as usize, TRACKED_SITES).find(|site| {
+                self.sites[*site].count == 0 || self.sites[*site].caller == allocation.caller
+            });
+            match site {
+                Some(site) => self.sites[site].add(allocation),
+                None => other_sites.add(allocation),
+            }
+        }
+        for site in self.sites.iter().filter(|site| site.count > 0) {
+            printf!(
+                b"%p: %d allocations, %d bytes, oldest from tick %d\n\0",
+                site.caller,
+                site.count,
+                site.bytes,
+                site.oldest
+            );
+        }
+        if other_sites.count > 0 {
+            printf!(
+                b"other call sites: %d allocations, %d bytes, oldest from tick %d\n\0",
+                other_sites.count,
+                other_sites.bytes,
+                other_sites.oldest
+            );
+        }
+        if self.dropped > 0 {
+            printf!(b"kalloc: %d allocations were not tracked\n\0", self.dropped);
+        }
+    }
+
+    fn find(&self, address: usize) -> Option<usize> {
+        // The low bits are the same for every aligned allocation
+        probe(address >> 4, TRACKED_ALLOCATIONS)
+            .take_while(|slot| self.slots[*slot].address != EMPTY)
+            .find(|slot| self.slots[*slot].address == address)
+    }
+}
+
+/// Every slot of a hash table of `len` slots, starting from the one `key` hashes to.
+/// `len` must be a power of two
+fn probe(key: usize, len: usize) -> impl Iterator<Item = usize> {
+    // Fibonacci hashing
+    let start = key.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> (usize::BITS - len.trailing_zeros());
+    (0..len).map(move |offset| (start + offset) % len)
+}
@@ -0,0 +1,105 @@
+use core::ffi::c_int;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::c_bindings;
+use crate::riscv_asm::{sfence_vma_asid, sfence_vma_page};
+use crate::sync::spinlock::Spintex;
+
+/// Bits of `proc.asid` holding the ASID itself. Its generation is kept above them
+const ASID_BITS: u32 = 16;
+
+/// The largest ASID the harts implement, or 0 if they have none,
+/// in which case every process shares the kernel's ASID 0
+static MAX_ASID: AtomicU64 = AtomicU64::new(0);
+
+/// The generation ASIDs are currently handed out from, readable without the allocator's lock
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+
+static ASIDS: Spintex<'static, AsidAllocator> = Spintex::new(
+    AsidAllocator {
+        generation: 1,
+        next: 1,
+    },
+    "asid",
+);
+
+/// Hands out ASIDs in generations. Once a generation runs out of ASIDs, the next one starts,
+/// and processes holding an ASID from an older generation get a new one when they next
+/// return to user space
+struct AsidAllocator {
+    generation: u64,
+    /// The next ASID to hand out in this generation. ASID 0 is the kernel's
+    next: u64,
+}
+
+/// Record the largest ASID the harts implement, found by probing satp at boot
+#[no_mangle]
+pub extern "C" fn asidinit(max_asid: c_bindings::uint64) {
+    MAX_ASID.store(max_asid, Ordering::Relaxed);
+}
+
+/// The ASID `proc` returns to user space with, allocating it a new one if its ASID
+/// is from an older generation. Sets `flush` if the TLB entries of that ASID on this hart
+/// have to be flushed first: when the ASID is new, since an earlier holder may have left
+/// entries behind, or when the process last returned to user space on another hart,
+/// since flushes made for its page table changes since then only reached that hart
+/// # Safety
+/// `proc` must be the current process, and `flush` valid to write
+#[no_mangle]
+pub unsafe extern "C" fn proc_asid(
+    proc: *mut c_bindings::proc_,
+    flush: *mut c_int,
+) -> c_bindings::uint64 {
+    let proc = unsafe { &mut *proc };
+    let max_asid = MAX_ASID.load(Ordering::Relaxed);
+    if max_asid == 0 {
+        unsafe { *flush = 1 };
+        return 0;
+    }
+    let cpu = unsafe { c_bindings::cpuid() };
+    let mut stale = proc.tlbcpu != cpu;
+    if proc.asid >> ASID_BITS != GENERATION.load(Ordering::Acquire) {
+        let mut asids = ASIDS.lock();
+        if asids.next > max_asid {
+            asids.generation += 1;
+            asids.next = 1;
+            GENERATION.store(asids.generation, Ordering::Release);
+        }
+        proc.asid = (asids.generation << ASID_BITS) | asids.next;
+        asids.next += 1;
+        stale = true;
+    }
+    proc.tlbcpu = cpu;
+    unsafe { *flush = c_int::from(stale) };
+    asid_of(proc)
+}
+
+/// Drop the TLB entries of `proc` after its page table changed. The current process's are
+/// flushed on this hart; any other process flushes its own when it next returns to user space
+/// # Safety
+/// `proc` must be a valid process that isn't running on another hart
+#[no_mangle]
+pub unsafe extern "C" fn proc_flush_tlb(proc: *mut c_bindings::proc_) {
+    let proc = unsafe { &mut *proc };
+    if ptr::eq(proc, unsafe { c_bindings::myproc() }) {
+        sfence_vma_asid!(asid_of(proc));
+    } else {
+        proc.tlbcpu = -1;
+    }
+}
+
+/// Drop the TLB entry of `proc` for the page at `va`, after its PTE changed,
+/// the way [`proc_flush_tlb`] drops all of them
+pub(crate) fn flush_tlb_page(proc: &mut c_bindings::proc_, va: u64) {
+    if ptr::eq(proc, unsafe { c_bindings::myproc() }) {
+        sfence_vma_page!(va, asid_of(proc));
+    } else {
+        proc.tlbcpu = -1;
+    }
+}
+
+/// The ASID `proc` last returned to user space with, or 0 if it has none
+pub(crate) fn asid_of(proc: &c_bindings::proc_) -> u64 {
+    proc.asid & ((1 << ASID_BITS) - 1)
+}
@@ -44,6 +44,7 @@ impl Console<'_> {
     const BACKSPACE_CHAR: u8 = 8;
     const BACKSPACE_CHAR_INT: i32 = 8;
     const CTRL_D: u8 = 4;
+    const CTRL_K: i32 = 11;
     const CTRL_P: i32 = 16;
     const CTRL_U: i32 = 21;
 
@@ -114,13 +115,12 @@ impl Console<'_> {
                 sleep_rust(cons_read_ptr, cons);
                 cons = self.cons.lock();
             }
+            // The byte stays in the buffer until it is copied
             let c = cons.buf[cons.read_index % cons.buf.len()];
-            cons.read_index = cons.read_index.wrapping_add(1);
             if c == Self::CTRL_D {
                 // EOF
                 if n < target {
                     // Save ^D for next time, to make sure caller gets a 0-byte result.
-                    cons.read_index = cons.read_index.wrapping_sub(1);
                     break;
                 }
             }
@@ -131,8 +131,21 @@ impl Console<'_> {
                 c_bindings::either_copyout(user_dst, dst, core::ptr::addr_of_mut!(cbuf).cast(), 1)
             } == -1
             {
-                break;
+                if user_dst == 0 {
+                    break;
+                }
+                // Swapping dst in, or reading it from a mapped file, sleeps,
+                // so it can't be done holding the console lock
+                Spintex::unlock(cons);
+                let brought_in =
+                    unsafe { crate::swap::uvmswapin((*c_bindings::myproc()).pagetable, dst, 1) };
+                cons = self.cons.lock();
+                if brought_in < 0 {
+                    break;
+                }
+                continue;
             }
+            cons.read_index = cons.read_index.wrapping_add(1);
 
             dst += 1;
             n -= 1;
@@ -152,6 +165,7 @@ impl Console<'_> {
             Self::CTRL_P => unsafe {
                 c_bindings::procdump();
             },
+            Self::CTRL_K => crate::kalloc::ALLOCATOR.dump_allocations(),
             Self::CTRL_U => {
                 while cons.edit_index != cons.write_index
                     && cons.buf[(cons.edit_index - 1) % cons.buf.len()] != b'\n'
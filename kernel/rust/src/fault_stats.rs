@@ -0,0 +1,58 @@
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::c_bindings;
+
+/// The kinds of page fault counted in `struct faultstats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Fault {
+    /// A page the process had reserved, or that was swapped out, was mapped
+    Minor,
+    /// A COW page was copied so it could be written to
+    CowCopy,
+    /// A COW page that nothing else referred to was made writeable in place
+    CowReuse,
+    /// An access to a bad address killed the process
+    Fatal,
+}
+
+static MINOR: AtomicU64 = AtomicU64::new(0);
+static COW_COPIES: AtomicU64 = AtomicU64::new(0);
+static COW_REUSES: AtomicU64 = AtomicU64::new(0);
+static FATAL: AtomicU64 = AtomicU64::new(0);
+
+/// Count a page fault of `proc`, both for it and for the whole system.
+/// Faults are taken by the process itself, so its own counts need no lock
+pub(crate) fn count(proc: &mut c_bindings::proc_, fault: Fault) {
+    let (own, total) = match fault {
+        Fault::Minor => (&mut proc.faults.minor, &MINOR),
+        Fault::CowCopy => (&mut proc.faults.cow_copies, &COW_COPIES),
+        Fault::CowReuse => (&mut proc.faults.cow_reuses, &COW_REUSES),
+        Fault::Fatal => (&mut proc.faults.fatal, &FATAL),
+    };
+    *own += 1;
+    total.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The page faults of every process since boot
+pub(crate) fn totals() -> c_bindings::faultstats {
+    c_bindings::faultstats {
+        minor: MINOR.load(Ordering::Relaxed),
+        cow_copies: COW_COPIES.load(Ordering::Relaxed),
+        cow_reuses: COW_REUSES.load(Ordering::Relaxed),
+        fatal: FATAL.load(Ordering::Relaxed),
+    }
+}
+
+/// The page faults of the process with `pid`, which may have exited but not been waited for.
+/// Returns None if there is no such process
+pub(crate) fn of_pid(pid: i32) -> Option<c_bindings::faultstats> {
+    let procs = unsafe { &mut *ptr::addr_of_mut!(c_bindings::proc_) };
+    procs.iter_mut().find_map(|proc| {
+        unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+        let faults =
+            (proc.state != c_bindings::procstate::UNUSED && proc.pid == pid).then_some(proc.faults);
+        unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+        faults
+    })
+}
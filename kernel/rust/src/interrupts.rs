@@ -43,3 +43,11 @@ pub extern "C" fn pop_off() {
         }
     }
 }
+
+/// Whether this CPU holds any spinlocks, which it would have to sleep holding to do disk I/O
+pub(crate) fn holding_spinlocks() -> bool {
+    push_off();
+    let held = unsafe { (*mycpu()).noff } > 1;
+    pop_off();
+    held
+}
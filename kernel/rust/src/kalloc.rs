@@ -1,44 +1,185 @@
 use crate::c_bindings;
 use crate::dev::device_load::PHYSICAL_ADDRESS_STOP;
+use crate::interrupts::{pop_off, push_off};
 use crate::printf::{panic, printf};
+use crate::slab::{SlabCache, SIZE_CLASSES};
 use crate::sync::spinlock::{Spintex, SpintexGuard};
 use crate::vm::{PGROUNDDOWN, PGROUNDUP};
+#[cfg(feature = "kalloc-track")]
+use crate::{alloc_tracker::AllocationTable, trap::TICKS_UNLOCKED};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::cell::Cell;
 use core::ptr::{self, null_mut, NonNull};
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
 
 const MEM_LOCK_NAME: &str = "kmem";
 const TINY_MEM_LOCK_NAME: &str = "kmem_tiny";
-const REFCOUNTS_LOCK_NAME: &str = "page_refcounts";
+const CPU_CACHE_LOCK_NAME: &str = "kmem_cpu";
+const ZEROED_POOL_LOCK_NAME: &str = "kmem_zeroed";
+#[cfg(feature = "kalloc-track")]
+const TRACKER_LOCK_NAME: &str = "kmem_track";
+
+/// Number of free pages each hart can hold in its cache
+const CPU_CACHE_SIZE: usize = 32;
+/// Number of pages moved between a hart's cache and the global freelist at once
+const CPU_CACHE_BATCH: usize = CPU_CACHE_SIZE / 2;
+/// Number of pre-zeroed pages kept ready for `alloc_zeroed`
+const ZEROED_POOL_SIZE: usize = 64;
+/// Pages zeroed per refill, so an idle hart is quickly back to scheduling
+const ZEROED_REFILL_BATCH: usize = 4;
+
+/// The largest block the page allocator hands out is `PGSIZE << MAX_ORDER` bytes
+pub(crate) const MAX_ORDER: usize = 10;
+/// Marker in the free order table for pages that do not start a free block
+const NOT_FREE: u8 = u8::MAX;
+
+/// The number of references held to a single physical page
+type PageRefcount = AtomicU16;
+
+/// Byte pattern written over freshly allocated memory
+const ALLOC_POISON: u8 = 5;
+/// Byte pattern written over freed memory
+const FREE_POISON: u8 = 1;
+/// Bytes of guard space on either side of a tiny allocation
+const REDZONE_SIZE: usize = if cfg!(feature = "kalloc-debug") {
+    KernelAllocator::MAX_ALIGNMENT
+} else {
+    0
+};
+/// Byte pattern of the redzones around tiny allocations
+#[cfg(feature = "kalloc-debug")]
+const REDZONE_POISON: u8 = 0xfd;
+/// Frames between [`return_address`] and the code that asked for memory:
+/// [`allocation_site`], `KernelAllocator::alloc` and the `__rust_alloc` shim
+const ALLOC_CALLER_DEPTH: usize = 3;
+/// Allocations at least this big skip the tiny list and go to the page allocator
+const TINY_LIMIT: usize =
+    c_bindings::PGSIZE as usize - 2 * core::mem::size_of::<TinyHeader>() - 2 * REDZONE_SIZE;
+
+/// Whether blocks of `size` bytes aligned to `align` come from the page allocator rather than
+/// the slab caches or the tiny list. Allocating, freeing and resizing in place all route by it
+const fn uses_page_allocator(size: usize, align: usize) -> bool {
+    size >= TINY_LIMIT || align >= c_bindings::PGSIZE as usize
+}
+
+/// Whether a tiny block with room for `block_size` bytes can be resized in place to hold
+/// `new_size` bytes, which it can only if they fit and would still be freed to the tiny list
+const fn tiny_fits_in_place(block_size: usize, new_size: usize, align: usize) -> bool {
+    block_size >= new_size && !uses_page_allocator(new_size, align)
+}
+
+// A tiny block as big as TINY_LIMIT moves when resized to TINY_LIMIT bytes, as they are freed
+// to the page allocator
+const _: () = {
+    assert!(tiny_fits_in_place(TINY_LIMIT, TINY_LIMIT - 1, 8));
+    assert!(!tiny_fits_in_place(TINY_LIMIT, TINY_LIMIT, 8));
+};
 
 #[repr(C)]
 struct Run {
     pub next: Cell<Option<NonNull<Run>>>,
+    pub prev: Cell<Option<NonNull<Run>>>,
+}
+
+/// The buddy allocator's free lists, one per block order
+struct FreeAreas<'a> {
+    lists: [Option<NonNull<Run>>; MAX_ORDER + 1],
+    /// The order of the free block starting at each page, or [`NOT_FREE`]
+    orders: Option<&'a mut [u8]>,
 }
 
 #[repr(C, align(16))]
 struct TinyHeader {
     next: Cell<Option<NonNull<TinyHeader>>>,
     size: usize,
+    /// Return address of the code that allocated this block
+    #[cfg(feature = "kalloc-debug")]
+    caller: u64,
+}
+
+/// A per-hart magazine of free pages, in front of the global freelist
+struct CpuPageCache {
+    pages: [*mut u8; CPU_CACHE_SIZE],
+    count: usize,
+    /// Allocations served straight from this cache
+    hits: u64,
+    /// Allocations that had to go to the global freelist
+    misses: u64,
+}
+
+/// Free pages that have already been zeroed, refilled while harts are idle
+struct ZeroedPagePool {
+    pages: [*mut u8; ZEROED_POOL_SIZE],
+    count: usize,
 }
 
 pub(crate) struct KernelPageAllocator<'a> {
-    freelist: Spintex<'a, Cell<Option<NonNull<Run>>>>,
-    page_refcounts: Spintex<'a, Cell<Option<&'a mut [u8]>>>,
+    freelist: Spintex<'a, FreeAreas<'a>>,
+    /// Set once by `init`, then only touched atomically
+    page_refcounts: Cell<Option<&'a [PageRefcount]>>,
+    /// Pages that are free, either on the freelist or in a hart's cache
+    free_pages: AtomicUsize,
+    /// Pages handed to the allocator by `init`
+    total_pages: AtomicUsize,
+    cpu_caches: [Spintex<'a, CpuPageCache>; c_bindings::NCPU as usize],
+    zeroed_pages: Spintex<'a, ZeroedPagePool>,
 }
 
 pub(crate) struct KernelAllocator<'a> {
     page_allocator: KernelPageAllocator<'a>,
+    slab_caches: [SlabCache<'a>; SIZE_CLASSES.len()],
     tiny_page_list: Spintex<'a, Cell<Option<NonNull<TinyHeader>>>>,
+    /// Every outstanding allocation, for finding leaks
+    #[cfg(feature = "kalloc-track")]
+    allocations: Spintex<'a, AllocationTable>,
 }
 
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_CPU_CACHE: Spintex<'static, CpuPageCache> = Spintex::new(
+    CpuPageCache {
+        pages: [ptr::null_mut(); CPU_CACHE_SIZE],
+        count: 0,
+        hits: 0,
+        misses: 0,
+    },
+    CPU_CACHE_LOCK_NAME,
+);
+
 #[global_allocator]
 pub(crate) static ALLOCATOR: KernelAllocator = KernelAllocator {
     page_allocator: KernelPageAllocator {
-        freelist: Spintex::new(Cell::new(None), MEM_LOCK_NAME),
-        page_refcounts: Spintex::new(Cell::new(None), REFCOUNTS_LOCK_NAME),
+        freelist: Spintex::new(
+            FreeAreas {
+                lists: [None; MAX_ORDER + 1],
+                orders: None,
+            },
+            MEM_LOCK_NAME,
+        ),
+        page_refcounts: Cell::new(None),
+        free_pages: AtomicUsize::new(0),
+        total_pages: AtomicUsize::new(0),
+        cpu_caches: [EMPTY_CPU_CACHE; c_bindings::NCPU as usize],
+        zeroed_pages: Spintex::new(
+            ZeroedPagePool {
+                pages: [ptr::null_mut(); ZEROED_POOL_SIZE],
+                count: 0,
+            },
+            ZEROED_POOL_LOCK_NAME,
+        ),
     },
+    slab_caches: [
+        SlabCache::new(SIZE_CLASSES[0]),
+        SlabCache::new(SIZE_CLASSES[1]),
+        SlabCache::new(SIZE_CLASSES[2]),
+        SlabCache::new(SIZE_CLASSES[3]),
+        SlabCache::new(SIZE_CLASSES[4]),
+        SlabCache::new(SIZE_CLASSES[5]),
+        SlabCache::new(SIZE_CLASSES[6]),
+        SlabCache::new(SIZE_CLASSES[7]),
+    ],
     tiny_page_list: Spintex::new(Cell::new(None), TINY_MEM_LOCK_NAME),
+    #[cfg(feature = "kalloc-track")]
+    allocations: Spintex::new(AllocationTable::new(), TRACKER_LOCK_NAME),
 };
 
 unsafe impl<'a> Sync for KernelPageAllocator<'a> {}
@@ -52,107 +193,149 @@ extern "C" {
 }
 
 unsafe impl<'a> GlobalAlloc for KernelPageAllocator<'a> {
-    /// Allocates a page of physical memory
-    /// Ignores `layout`, except to check that the request is for no more than a page of memory
+    /// Allocates a physically contiguous, naturally aligned block of `2^order` pages,
+    /// where `order` is the smallest order that fits both the size and alignment of `layout`
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let align = layout.align();
-
-        // Cannot allocate or align more than a page
-        if size > c_bindings::PGSIZE as usize || align > c_bindings::PGSIZE as usize {
+        let Some(order) = Self::order_for(layout) else {
             return ptr::null_mut();
-        }
+        };
 
-        let freelist = self.freelist.lock();
-        let return_cell = Cell::new(None);
-        return_cell.swap(&freelist);
-        if let Some(run_cell) = return_cell.get() {
-            freelist.swap(&run_cell.as_ref().next);
+        // Single pages come from this hart's cache, to stay off of the global freelist lock
+        if order == 0 {
+            let page = self.alloc_cached_page();
+            if !page.is_null() {
+                self.free_pages.fetch_sub(1, Ordering::Relaxed);
+                #[cfg(feature = "kalloc-debug")]
+                Self::check_free_poison(page, 0);
+                self.add_block_references(page, 0);
+                ptr::write_bytes(page, ALLOC_POISON, c_bindings::PGSIZE as usize);
+            }
+            return page;
         }
 
-        match return_cell.get().map(NonNull::cast::<u8>) {
+        let mut freelist = self.freelist.lock();
+        match freelist.take_block(order).map(NonNull::cast::<u8>) {
             None => null_mut(),
             Some(ptr) => {
                 let final_ptr = ptr.as_ptr();
-                {
-                    let page_refcounts = self.page_refcounts.lock();
-                    let refcount_data = page_refcounts.take().unwrap();
-                    // The index in the refcount data to update.
-                    let page_index = self.convert_physical_to_index(final_ptr as usize);
-                    refcount_data[page_index] += 1;
-                    page_refcounts.set(Some(refcount_data));
-                }
+                self.free_pages.fetch_sub(1 << order, Ordering::Relaxed);
+                self.add_block_references(final_ptr, order);
                 Spintex::unlock(freelist);
-                ptr::write_bytes(final_ptr, 5, c_bindings::PGSIZE as usize);
+                #[cfg(feature = "kalloc-debug")]
+                Self::check_free_poison(final_ptr, order);
+                ptr::write_bytes(
+                    final_ptr,
+                    ALLOC_POISON,
+                    (c_bindings::PGSIZE as usize) << order,
+                );
                 final_ptr
             }
         }
     }
 
-    /// Deallocate a page allocated by this allocator
-    #[allow(clippy::cast_ptr_alignment)]
+    /// Single pages come from the pool of pre-zeroed pages when it has any
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if Self::order_for(layout) == Some(0) {
+            let page = self.zeroed_pages.lock().pop();
+            if !page.is_null() {
+                self.free_pages.fetch_sub(1, Ordering::Relaxed);
+                self.add_block_references(page, 0);
+                return page;
+            }
+        }
+
+        let ptr = self.alloc(layout);
+        if !ptr.is_null() {
+            ptr::write_bytes(ptr, 0, layout.size());
+        }
+        ptr
+    }
+
+    /// Deallocate a block allocated by this allocator
+    /// Every page in the block loses a reference. If the whole block is unreferenced it
+    /// is freed as one block, otherwise only the unreferenced pages are freed.
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        let align = layout.align();
         let ptr_int = ptr as usize;
-        if ptr_int % c_bindings::PGSIZE as usize != 0
+        let Some(order) = Self::order_for(layout) else {
+            panic!("KPA_dealloc: Out of bounds\0");
+        };
+        let block_size = (c_bindings::PGSIZE as usize) << order;
+        if ptr_int % block_size != 0
             || ptr_int < end as usize
-            || ptr_int >= usize::try_from(PHYSICAL_ADDRESS_STOP).unwrap()
-            || size > c_bindings::PGSIZE as usize
-            || align > c_bindings::PGSIZE as usize
+            || ptr_int + block_size > usize::try_from(PHYSICAL_ADDRESS_STOP).unwrap()
         {
             panic!("KPA_dealloc: Out of bounds\0");
         }
 
-        // Lock any modifications to the freelist for the remainder of the execution
-        // We want to make sure that we don't deadlock, and that we don't change the refcount before deallocating
-        // We also want to have the same lock order as alloc
-        let freelist = self.freelist.lock();
+        // Previous checks ensure this index, and the rest of the block's, are in bounds
+        let page_index = Self::convert_physical_to_index(ptr_int);
 
-        let page_refcounts = self.page_refcounts.lock();
-        let refcount = {
-            let refcount_data = page_refcounts.take().unwrap();
-            // The index in the refcount data to update. Previous checks ensure this is in bounds
-            let page_index = self.convert_physical_to_index(ptr_int);
-            // Panic if no references were loaned out to the Kernel
-            let mut refcount = refcount_data[page_index];
-            if refcount == 0 {
-                panic!("KPA_dealloc: No page references\0");
-            }
-            // Remove a reference to this page
-            refcount -= 1;
-            refcount_data[page_index] = refcount;
-            page_refcounts.set(Some(refcount_data));
-            refcount
-        };
-        Spintex::unlock(page_refcounts);
+        // Only the hart that drops the last reference to a page frees it.
+        // Hold this hart's cache lock while dropping it, so the page is released together
+        // with its final drop to zero
+        if order == 0 {
+            let mut cache = self.cpu_caches[Self::cpu_index()].lock();
+            if self.drop_reference(page_index) {
+                ptr::write_bytes(ptr, FREE_POISON, c_bindings::PGSIZE as usize);
+                self.cache_page(&mut cache, ptr);
+                self.free_pages.fetch_add(1, Ordering::Relaxed);
+            }
+            return;
+        }
+
+        // Bigger blocks hold the freelist lock instead, as they are released to the freelist
+        let mut freelist = self.freelist.lock();
+
+        // Pages that this call dropped the last reference to
+        let mut unreferenced = [0u64; (1 << MAX_ORDER) / u64::BITS as usize];
+        let mut unreferenced_count = 0;
+        for page in 0..1usize << order {
+            if self.drop_reference(page_index + page) {
+                unreferenced[page / u64::BITS as usize] |= 1 << (page % u64::BITS as usize);
+                unreferenced_count += 1;
+            }
+        }
 
-        // Only actually deallocate if we have 0 references
-        if refcount == 0 {
-            ptr::write_bytes(ptr, 1, c_bindings::PGSIZE as usize);
-            let run_ref_option: Option<&'static mut Run> = ptr.cast::<Run>().as_mut();
-            if let Some(run_ref) = run_ref_option {
-                run_ref.next = Cell::new(None);
-                run_ref.next.swap(&freelist);
-                let run_cell = Cell::new(Some(NonNull::new_unchecked(run_ref as *mut Run)));
-                freelist.swap(&run_cell);
+        self.free_pages
+            .fetch_add(unreferenced_count, Ordering::Relaxed);
+        // Only actually deallocate pages with 0 references
+        if unreferenced_count == 1 << order {
+            ptr::write_bytes(ptr, FREE_POISON, block_size);
+            freelist.release_block(ptr, order);
+        } else {
+            for page in 0..1usize << order {
+                if unreferenced[page / u64::BITS as usize] & (1 << (page % u64::BITS as usize)) != 0
+                {
+                    let page_ptr = ptr.add(page * c_bindings::PGSIZE as usize);
+                    ptr::write_bytes(page_ptr, FREE_POISON, c_bindings::PGSIZE as usize);
+                    freelist.release_block(page_ptr, 0);
+                }
             }
         }
     }
 }
 
 impl KernelPageAllocator<'_> {
+    #[allow(clippy::cast_ptr_alignment)]
     pub fn init(&self, page_count: usize) {
+        // `end` is 16 byte aligned, so the refcounts can sit right on it
+        let refcount_bytes = page_count * core::mem::size_of::<PageRefcount>();
+        let refcount_data =
+            unsafe { core::slice::from_raw_parts_mut(end as *mut PageRefcount, page_count) };
+        for refcount in refcount_data.iter_mut() {
+            *refcount = AtomicU16::new(1);
+        }
         unsafe {
-            core::ptr::write_bytes(end as *mut u8, 1, page_count);
+            core::ptr::write_bytes((end as *mut u8).add(refcount_bytes), NOT_FREE, page_count);
         }
-        let refcount_cell = self.page_refcounts.lock();
-        refcount_cell.set(Some(unsafe {
-            core::slice::from_raw_parts_mut(end as *mut u8, page_count)
-        }));
-        Spintex::unlock(refcount_cell);
+        self.page_refcounts.set(Some(refcount_data));
+        let mut freelist = self.freelist.lock();
+        freelist.orders = Some(unsafe {
+            core::slice::from_raw_parts_mut((end as *mut u8).add(refcount_bytes), page_count)
+        });
+        Spintex::unlock(freelist);
 
-        let mut ptr = PGROUNDUP!(end as usize + page_count) as *mut u8;
+        let mut ptr = PGROUNDUP!(end as usize + refcount_bytes + page_count) as *mut u8;
         let layout = unsafe {
             Layout::from_size_align_unchecked(
                 c_bindings::PGSIZE as usize,
@@ -166,23 +349,241 @@ impl KernelPageAllocator<'_> {
             unsafe {
                 self.dealloc(ptr, layout);
             }
+            self.total_pages.fetch_add(1, Ordering::Relaxed);
             ptr = unsafe { ptr.byte_add(c_bindings::PGSIZE as usize) };
         }
     }
 
     pub(crate) fn pfree_count(&self) -> u64 {
-        let mut free_memory = 0u64;
-        let freelist = self.freelist.lock();
-        let mut optional_run_ref = freelist.get();
-        while optional_run_ref.is_some() {
-            free_memory += u64::from(c_bindings::PGSIZE);
-            optional_run_ref = optional_run_ref.and_then(|ptr| unsafe { ptr.as_ref() }.next.get());
+        u64::from(c_bindings::PGSIZE) * self.free_pages() as u64
+    }
+
+    /// The number of free pages, including those in each hart's cache
+    pub(crate) fn free_pages(&self) -> usize {
+        self.free_pages.load(Ordering::Relaxed)
+    }
+
+    /// The number of pages managed by this allocator
+    pub(crate) fn total_pages(&self) -> usize {
+        self.total_pages.load(Ordering::Relaxed)
+    }
+
+    /// The smallest block order that satisfies both the size and alignment of `layout`,
+    /// or `None` if the layout is larger than the biggest block
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn order_for(layout: Layout) -> Option<usize> {
+        let pages = layout
+            .size()
+            .max(layout.align())
+            .div_ceil(c_bindings::PGSIZE as usize)
+            .max(1);
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        (order <= MAX_ORDER).then_some(order)
+    }
+
+    /// Per-hart page cache hit and miss counts, indexed by hart
+    pub(crate) fn cpu_cache_stats(
+        &self,
+    ) -> (
+        [u64; c_bindings::NCPU as usize],
+        [u64; c_bindings::NCPU as usize],
+    ) {
+        let mut hits = [0u64; c_bindings::NCPU as usize];
+        let mut misses = [0u64; c_bindings::NCPU as usize];
+        for (cpu, cache) in self.cpu_caches.iter().enumerate() {
+            let cache = cache.lock();
+            hits[cpu] = cache.hits;
+            misses[cpu] = cache.misses;
+        }
+        (hits, misses)
+    }
+
+    /// Index of the hart we are running on, found via `mycpu()`
+    fn cpu_index() -> usize {
+        push_off();
+        let index = unsafe {
+            c_bindings::mycpu()
+                .offset_from(ptr::addr_of!(c_bindings::cpus).cast::<c_bindings::cpu>())
+        };
+        pop_off();
+        usize::try_from(index).unwrap()
+    }
+
+    /// Take a page from this hart's cache, refilling the cache from the global freelist in a batch if empty
+    /// Returns null if no hart has a free page left
+    unsafe fn alloc_cached_page(&self) -> *mut u8 {
+        let mut cache = self.cpu_caches[Self::cpu_index()].lock();
+        if cache.count > 0 {
+            cache.hits += 1;
+        } else {
+            cache.misses += 1;
+            let mut freelist = self.freelist.lock();
+            while cache.count < CPU_CACHE_BATCH {
+                match freelist.take_block(0) {
+                    None => break,
+                    Some(page) => cache.push(page.as_ptr().cast()),
+                }
+            }
+        }
+        let page = cache.pop();
+        Spintex::unlock(cache);
+        if !page.is_null() {
+            return page;
+        }
+
+        // Other harts may still be holding on to free pages, so hand them all back and retry
+        self.drain_cpu_caches();
+        if let Some(page) = self.freelist.lock().take_block(0) {
+            return page.as_ptr().cast();
+        }
+
+        // The last free pages may have been zeroed ahead of time
+        let page = self.zeroed_pages.lock().pop();
+        if cfg!(feature = "kalloc-debug") && !page.is_null() {
+            ptr::write_bytes(page, FREE_POISON, c_bindings::PGSIZE as usize);
+        }
+        page
+    }
+
+    /// Zero a few free pages into the pool used by `alloc_zeroed`
+    /// Meant for harts with nothing else to do
+    pub(crate) fn refill_zeroed_pages(&self) {
+        for _ in 0..ZEROED_REFILL_BATCH {
+            if self.zeroed_pages.lock().count == ZEROED_POOL_SIZE {
+                return;
+            }
+            let page = self.take_uncounted_page();
+            if page.is_null() {
+                return;
+            }
+            #[cfg(feature = "kalloc-debug")]
+            unsafe {
+                Self::check_free_poison(page, 0);
+            }
+            unsafe { ptr::write_bytes(page, 0, c_bindings::PGSIZE as usize) };
+
+            let mut pool = self.zeroed_pages.lock();
+            if pool.count == ZEROED_POOL_SIZE {
+                // Another hart filled the pool first
+                Spintex::unlock(pool);
+                unsafe {
+                    ptr::write_bytes(page, FREE_POISON, c_bindings::PGSIZE as usize);
+                    self.free_cached_page(page);
+                }
+                return;
+            }
+            pool.push(page);
+        }
+    }
+
+    /// Take a free page from this hart's cache, or else the global freelist, without counting
+    /// a cache hit or miss, so pages taken in the background don't skew the hit rate
+    /// Returns null if neither has a free page
+    fn take_uncounted_page(&self) -> *mut u8 {
+        let page = self.cpu_caches[Self::cpu_index()].lock().pop();
+        if !page.is_null() {
+            return page;
+        }
+        self.freelist
+            .lock()
+            .take_block(0)
+            .map_or(null_mut(), |page| page.as_ptr().cast())
+    }
+
+    /// Put an unreferenced page into this hart's cache, draining a batch to the global freelist if full
+    unsafe fn free_cached_page(&self, page: *mut u8) {
+        let mut cache = self.cpu_caches[Self::cpu_index()].lock();
+        unsafe { self.cache_page(&mut cache, page) };
+    }
+
+    /// Put an unreferenced page into `cache`, draining a batch to the global freelist if full
+    unsafe fn cache_page(&self, cache: &mut CpuPageCache, page: *mut u8) {
+        if cache.count == CPU_CACHE_SIZE {
+            let mut freelist = self.freelist.lock();
+            for _ in 0..CPU_CACHE_BATCH {
+                freelist.release_block(cache.pop(), 0);
+            }
+        }
+        cache.push(page);
+    }
+
+    /// Return every hart's cached pages to the global freelist
+    unsafe fn drain_cpu_caches(&self) {
+        for cache in &self.cpu_caches {
+            let mut cache = cache.lock();
+            let mut freelist = self.freelist.lock();
+            while cache.count > 0 {
+                freelist.release_block(cache.pop(), 0);
+            }
+        }
+    }
+
+    /// Add a reference to every page in the block at `block`
+    fn add_block_references(&self, block: *mut u8, order: usize) {
+        // The indices in the refcount data to update, one for each page in the block.
+        let page_index = Self::convert_physical_to_index(block as usize);
+        for page in 0..1usize << order {
+            self.add_reference(
+                page_index + page,
+                block as usize + page * c_bindings::PGSIZE as usize,
+            );
+        }
+    }
+
+    /// Panic if a free block of `2^order` pages was written to since it was freed
+    /// Only the start of each page may differ, where the free lists keep their links
+    #[cfg(feature = "kalloc-debug")]
+    unsafe fn check_free_poison(block: *mut u8, order: usize) {
+        for page in 0..1usize << order {
+            let page_ptr = block.add(page * c_bindings::PGSIZE as usize);
+            let poisoned = core::slice::from_raw_parts(
+                page_ptr.add(core::mem::size_of::<Run>()),
+                c_bindings::PGSIZE as usize - core::mem::size_of::<Run>(),
+            );
+            if let Some(offset) = poisoned.iter().position(|byte| *byte != FREE_POISON) {
+                printf!(
+                    b"kalloc: free page %p modified at %p\n\0",
+                    page_ptr,
+                    page_ptr.add(core::mem::size_of::<Run>() + offset)
+                );
+                panic!("kalloc: heap corruption\0");
+            }
+        }
+    }
+
+    /// Add a reference to the page at `page_index`, panicking if its count would overflow
+    fn add_reference(&self, page_index: usize, physical_address: usize) {
+        let refcounts = self.page_refcounts.get().unwrap();
+        if refcounts[page_index]
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |refcount| {
+                refcount.checked_add(1)
+            })
+            .is_err()
+        {
+            printf!(
+                b"kalloc: page %p has more than %d references\n\0",
+                physical_address,
+                u32::from(u16::MAX)
+            );
+            panic!("KPA: refcount overflow\0");
+        }
+    }
+
+    /// Drop a reference to the page at `page_index`
+    /// Returns true if that was the last reference, so the page should be freed
+    fn drop_reference(&self, page_index: usize) -> bool {
+        let refcounts = self.page_refcounts.get().unwrap();
+        match refcounts[page_index].fetch_update(Ordering::AcqRel, Ordering::Acquire, |refcount| {
+            refcount.checked_sub(1)
+        }) {
+            Ok(previous) => previous == 1,
+            // Panic if no references were loaned out to the Kernel
+            Err(_) => panic!("KPA_dealloc: No page references\0"),
         }
-        free_memory
     }
 
     #[inline]
-    fn convert_physical_to_index(&self, physical_address: usize) -> usize {
+    fn convert_physical_to_index(physical_address: usize) -> usize {
         usize::try_from(PGROUNDDOWN!(
             physical_address - usize::try_from(PGROUNDUP!(end as usize)).unwrap()
         ))
@@ -191,42 +592,265 @@ impl KernelPageAllocator<'_> {
     }
 
     pub fn in_place_copy(&self, physical_address: usize) {
-        let index = self.convert_physical_to_index(physical_address);
-        let refcounts = self.page_refcounts.lock();
-        let refcount_data = refcounts.take().unwrap();
-        refcount_data[index] += 1;
-        refcounts.set(Some(refcount_data));
+        self.add_reference(
+            Self::convert_physical_to_index(physical_address),
+            physical_address,
+        );
     }
 
     pub(crate) fn exactly_one_reference(&self, physical_address: usize) -> bool {
-        let index = self.convert_physical_to_index(physical_address);
-        let reference_counts = self.page_refcounts.lock();
-        let reference_data = reference_counts.take().unwrap();
-        let is_exactly_one_reference = reference_data[index] == 1;
-        reference_counts.set(Some(reference_data));
-        is_exactly_one_reference
+        let index = Self::convert_physical_to_index(physical_address);
+        self.page_refcounts.get().unwrap()[index].load(Ordering::Acquire) == 1
     }
 }
 
-unsafe impl GlobalAlloc for KernelAllocator<'_> {
+impl ZeroedPagePool {
+    fn push(&mut self, page: *mut u8) {
+        self.pages[self.count] = page;
+        self.count += 1;
+    }
+
+    /// Remove the most recently zeroed page, or null if the pool is empty
+    fn pop(&mut self) -> *mut u8 {
+        if self.count == 0 {
+            return ptr::null_mut();
+        }
+        self.count -= 1;
+        self.pages[self.count]
+    }
+}
+
+impl CpuPageCache {
+    fn push(&mut self, page: *mut u8) {
+        self.pages[self.count] = page;
+        self.count += 1;
+    }
+
+    /// Remove the most recently cached page, or null if the cache is empty
+    fn pop(&mut self) -> *mut u8 {
+        if self.count == 0 {
+            return ptr::null_mut();
+        }
+        self.count -= 1;
+        self.pages[self.count]
+    }
+}
+
+impl FreeAreas<'_> {
+    /// Remove a free block of exactly `order` from the free lists, splitting a larger block if needed
+    fn take_block(&mut self, order: usize) -> Option<NonNull<Run>> {
+        let found_order = (order..=MAX_ORDER).find(|order| self.lists[*order].is_some())?;
+        let block = self.lists[found_order].unwrap();
+        unsafe { self.remove(block, found_order) };
+
+        // Give back the upper half of the block until it is the requested size
+        for split_order in (order..found_order).rev() {
+            unsafe {
+                let buddy = block
+                    .as_ptr()
+                    .byte_add((c_bindings::PGSIZE as usize) << split_order);
+                self.push(buddy.cast(), split_order);
+            }
+        }
+        Some(block)
+    }
+
+    /// Return a block to the free lists, merging it with its buddy for as long as the buddy is also free
+    /// # Safety
+    /// `block` must be an unreferenced block of `2^order` pages, aligned to its size
+    unsafe fn release_block(&mut self, block: *mut u8, order: usize) {
+        let managed_start = usize::try_from(PGROUNDUP!(end as usize)).unwrap();
+        let managed_stop = usize::try_from(PHYSICAL_ADDRESS_STOP).unwrap();
+        let mut block = block as usize;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let block_size = (c_bindings::PGSIZE as usize) << order;
+            let buddy = block ^ block_size;
+            if buddy < managed_start || buddy + block_size > managed_stop {
+                break;
+            }
+            let buddy_index = KernelPageAllocator::convert_physical_to_index(buddy);
+            if self.orders.as_ref().unwrap()[buddy_index] != u8::try_from(order).unwrap() {
+                break;
+            }
+            self.remove(NonNull::new_unchecked(buddy as *mut Run), order);
+            // The upper half's list links are now just data inside the merged block
+            ptr::write_bytes(
+                block.max(buddy) as *mut u8,
+                FREE_POISON,
+                core::mem::size_of::<Run>(),
+            );
+            block = block.min(buddy);
+            order += 1;
+        }
+        self.push(block as *mut u8, order);
+    }
+
+    /// Push a free block onto the head of the list for `order`
     #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn push(&mut self, block: *mut u8, order: usize) {
+        let run = block.cast::<Run>();
+        run.write(Run {
+            next: Cell::new(self.lists[order]),
+            prev: Cell::new(None),
+        });
+        let run = NonNull::new_unchecked(run);
+        if let Some(next) = self.lists[order] {
+            next.as_ref().prev.set(Some(run));
+        }
+        self.lists[order] = Some(run);
+        self.orders.as_mut().unwrap()
+            [KernelPageAllocator::convert_physical_to_index(block as usize)] =
+            u8::try_from(order).unwrap();
+    }
+
+    /// Unlink a free block from the list for `order`
+    unsafe fn remove(&mut self, run: NonNull<Run>, order: usize) {
+        let next = run.as_ref().next.get();
+        let prev = run.as_ref().prev.get();
+        match prev {
+            None => self.lists[order] = next,
+            Some(prev) => prev.as_ref().next.set(next),
+        }
+        if let Some(next) = next {
+            next.as_ref().prev.set(prev);
+        }
+        self.orders.as_mut().unwrap()
+            [KernelPageAllocator::convert_physical_to_index(run.as_ptr() as usize)] = NOT_FREE;
+    }
+}
+
+unsafe impl GlobalAlloc for KernelAllocator<'_> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let caller = allocation_site();
+        let ptr = self.alloc_block(layout, caller);
+        #[cfg(feature = "kalloc-track")]
+        self.track_allocation(ptr, layout, caller);
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let caller = allocation_site();
+        // Whole pages may already be zeroed
+        let ptr = if uses_page_allocator(layout.size(), layout.align()) {
+            self.page_allocator.alloc_zeroed(layout)
+        } else {
+            let ptr = self.alloc_block(layout, caller);
+            if !ptr.is_null() {
+                ptr::write_bytes(ptr, 0, layout.size());
+            }
+            ptr
+        };
+        #[cfg(feature = "kalloc-track")]
+        self.track_allocation(ptr, layout, caller);
+        ptr
+    }
+
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "kalloc-track")]
+        self.allocations.lock().remove(ptr as usize);
+
+        let size = layout.size();
+        let align = layout.align();
+
+        // Pass off deallocations greater or equal to a page to the page allocator
+        // Size will delegate to the page allocator if it is bigger than
+        if uses_page_allocator(size, align) {
+            self.page_allocator.dealloc(ptr, layout);
+        } else {
+            let ptr_int = ptr as usize;
+            if ptr_int % Self::MAX_ALIGNMENT != 0
+                || ptr_int < end as usize
+                || ptr_int >= usize::try_from(PHYSICAL_ADDRESS_STOP).unwrap()
+                || size > c_bindings::PGSIZE as usize
+                || align > c_bindings::PGSIZE as usize
+            {
+                panic!("KTA_dealloc: Out of bounds\0");
+            }
+
+            if let Some(cache) = self.slab_cache_for(size) {
+                cache.dealloc(ptr, &self.page_allocator);
+                return;
+            }
+
+            let data = ptr.sub(REDZONE_SIZE);
+            #[cfg(feature = "kalloc-debug")]
+            Self::check_tiny_redzones(data, size);
+            let tiny_list = self.tiny_page_list.lock();
+            let header = NonNull::new_unchecked(data.cast::<TinyHeader>().offset(-1));
+            if cfg!(feature = "kalloc-debug") {
+                ptr::write_bytes(data, FREE_POISON, header.as_ref().size);
+            }
+            self.release_tiny_block(&tiny_list, header);
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.realloc_block(ptr, layout, new_size);
+        // Moved blocks were already tracked through `alloc` and `dealloc`
+        #[cfg(feature = "kalloc-track")]
+        if new_ptr == ptr {
+            let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+            self.allocations.lock().resize(ptr as usize, new_layout);
+        }
+        new_ptr
+    }
+}
+
+impl KernelAllocator<'_> {
+    const MAX_ALIGNMENT: usize = 16;
+
+    pub fn init(&self, page_count: usize) {
+        self.page_allocator.init(page_count);
+    }
+
+    pub(crate) fn cpu_cache_stats(
+        &self,
+    ) -> (
+        [u64; c_bindings::NCPU as usize],
+        [u64; c_bindings::NCPU as usize],
+    ) {
+        self.page_allocator.cpu_cache_stats()
+    }
+
+    /// The number of free physical pages
+    pub(crate) fn free_pages(&self) -> usize {
+        self.page_allocator.free_pages()
+    }
+
+    pub(crate) fn refill_zeroed_pages(&self) {
+        self.page_allocator.refill_zeroed_pages();
+    }
+
+    /// The number of physical pages the allocator manages
+    pub(crate) fn total_pages(&self) -> usize {
+        self.page_allocator.total_pages()
+    }
+
+    /// Allocate a block for `layout` on behalf of `caller`
+    #[allow(clippy::cast_ptr_alignment)]
+    #[cfg_attr(not(feature = "kalloc-debug"), allow(unused_variables))]
+    unsafe fn alloc_block(&self, layout: Layout, caller: u64) -> *mut u8 {
         let size = layout.size();
         let align = layout.align();
 
         // Pass off allocations greater or equal to a page to the page allocator
         // Size will delegate to the page allocator if it is bigger than
-        if size >= (c_bindings::PGSIZE as usize - (2 * core::mem::size_of::<TinyHeader>()))
-            || align >= c_bindings::PGSIZE as usize
-        {
+        if uses_page_allocator(size, align) {
             self.page_allocator.alloc(layout)
         } else {
             if align > Self::MAX_ALIGNMENT {
                 return ptr::null_mut();
             }
-            let size = (size + Self::MAX_ALIGNMENT - 1) & !(Self::MAX_ALIGNMENT - 1);
+            // Small objects come from the slab cache of their size class
+            if let Some(cache) = self.slab_cache_for(size) {
+                return cache.alloc(&self.page_allocator);
+            }
+            let size =
+                ((size + Self::MAX_ALIGNMENT - 1) & !(Self::MAX_ALIGNMENT - 1)) + 2 * REDZONE_SIZE;
             let tiny_list = self.tiny_page_list.lock();
-            if let Some(list) = tiny_list.get() {
+            let data = if let Some(list) = tiny_list.get() {
                 let mut header = list;
                 let mut prev: Option<NonNull<TinyHeader>> = None;
                 let data = loop {
@@ -244,114 +868,59 @@ unsafe impl GlobalAlloc for KernelAllocator<'_> {
                     header = header_mut.next.get().unwrap();
                 };
                 if data.is_null() {
-                    let page_layout = Layout::from_size_align_unchecked(
-                        c_bindings::PGSIZE as usize,
-                        c_bindings::PGSIZE as usize,
-                    );
-                    let new_page = self.page_allocator.alloc(page_layout);
-                    if new_page.is_null() {
-                        new_page
-                    } else {
-                        let free_header = new_page
-                            .add(size + core::mem::size_of::<TinyHeader>())
-                            .cast::<TinyHeader>();
-                        unsafe {
-                            *free_header = TinyHeader {
-                                next: Cell::new(tiny_list.get()),
-                                size: c_bindings::PGSIZE as usize
-                                    - (size + 2 * core::mem::size_of::<TinyHeader>()),
-                            }
-                        };
-                        unsafe {
-                            *new_page.cast::<TinyHeader>() = TinyHeader {
-                                next: Cell::new(None),
-                                size,
-                            }
-                        }
-                        tiny_list.set(NonNull::new(free_header));
-                        new_page.cast::<TinyHeader>().add(1).cast()
-                    }
+                    self.alloc_tiny_page(&tiny_list, size)
                 } else {
                     data
                 }
             } else {
-                let page_layout = Layout::from_size_align_unchecked(
-                    c_bindings::PGSIZE as usize,
-                    c_bindings::PGSIZE as usize,
-                );
-                let new_page = self.page_allocator.alloc(page_layout);
-                if new_page.is_null() {
-                    new_page
-                } else {
-                    let free_header = new_page
-                        .add(size + core::mem::size_of::<TinyHeader>())
-                        .cast::<TinyHeader>();
-                    unsafe {
-                        *free_header = TinyHeader {
-                            next: Cell::new(None),
-                            size: c_bindings::PGSIZE as usize
-                                - (size + 2 * core::mem::size_of::<TinyHeader>()),
-                        }
-                    };
-                    unsafe {
-                        *new_page.cast::<TinyHeader>() = TinyHeader {
-                            next: Cell::new(None),
-                            size,
-                        }
-                    }
-                    tiny_list.set(NonNull::new(free_header));
-                    new_page.cast::<TinyHeader>().offset(1).cast()
-                }
-            }
-        }
-    }
-
-    #[allow(clippy::cast_ptr_alignment)]
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        let align = layout.align();
-
-        // Pass off deallocations greater or equal to a page to the page allocator
-        // Size will delegate to the page allocator if it is bigger than
-        if size >= (c_bindings::PGSIZE as usize - (2 * core::mem::size_of::<TinyHeader>()))
-            || align >= c_bindings::PGSIZE as usize
-        {
-            self.page_allocator.dealloc(ptr, layout);
-        } else {
-            let ptr_int = ptr as usize;
-            if ptr_int % Self::MAX_ALIGNMENT != 0
-                || ptr_int < end as usize
-                || ptr_int >= usize::try_from(PHYSICAL_ADDRESS_STOP).unwrap()
-                || size > c_bindings::PGSIZE as usize
-                || align > c_bindings::PGSIZE as usize
-            {
-                panic!("KTA_dealloc: Out of bounds\0");
+                self.alloc_tiny_page(&tiny_list, size)
+            };
+            Spintex::unlock(tiny_list);
+            if data.is_null() {
+                return data;
             }
-
-            let header_list = self.tiny_page_list.lock();
-            let header = unsafe { ptr.cast::<TinyHeader>().offset(-1).as_mut().unwrap() };
-            header.next = Cell::new(header_list.get());
-            header_list.set(Some(header.into()));
+            #[cfg(feature = "kalloc-debug")]
+            Self::guard_tiny_block(data, layout.size(), caller);
+            data.add(REDZONE_SIZE)
         }
     }
 
     #[allow(clippy::cast_ptr_alignment)]
-    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+    unsafe fn realloc_block(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let old_size = layout.size();
         let align = layout.align();
         if align >= c_bindings::PGSIZE as usize {
-            self.page_allocator.realloc(ptr, layout, new_size)
-        } else if old_size >= c_bindings::PGSIZE as usize - (2 * core::mem::size_of::<TinyHeader>())
-        {
-            if new_size <= old_size {
+            self.default_realloc(ptr, layout, new_size)
+        } else if uses_page_allocator(old_size, align) {
+            // Keep the block only if the new size would be freed as the same block
+            let new_layout = Layout::from_size_align_unchecked(new_size, align);
+            if uses_page_allocator(new_size, align)
+                && KernelPageAllocator::order_for(new_layout)
+                    == KernelPageAllocator::order_for(layout)
+            {
+                ptr
+            } else {
+                self.default_realloc(ptr, layout, new_size)
+            }
+        } else if let Some(cache) = self.slab_cache_for(old_size) {
+            // Objects can only stay put if they would be freed back to the same cache
+            if self
+                .slab_cache_for(new_size)
+                .is_some_and(|new_cache| ptr::eq(new_cache, cache))
+            {
                 ptr
             } else {
-                self.page_allocator.realloc(ptr, layout, new_size)
+                self.default_realloc(ptr, layout, new_size)
             }
+        } else if cfg!(feature = "kalloc-debug") {
+            // Growing in place would leave the old redzones inside the block
+            self.default_realloc(ptr, layout, new_size)
         } else {
             match unsafe { ptr.cast::<TinyHeader>().sub(1).as_ref() } {
                 Some(header) => {
-                    if header.size >= new_size {
+                    if tiny_fits_in_place(header.size, new_size, align)
+                        && self.slab_cache_for(new_size).is_none()
+                    {
                         ptr
                     } else {
                         self.default_realloc(ptr, layout, new_size)
@@ -361,13 +930,55 @@ unsafe impl GlobalAlloc for KernelAllocator<'_> {
             }
         }
     }
-}
 
-impl KernelAllocator<'_> {
-    const MAX_ALIGNMENT: usize = 16;
+    /// Record a successful allocation in the allocation table
+    #[cfg(feature = "kalloc-track")]
+    unsafe fn track_allocation(&self, ptr: *mut u8, layout: Layout, caller: u64) {
+        if !ptr.is_null() {
+            let tick = TICKS_UNLOCKED.load(Ordering::Relaxed);
+            let size = self.reserved_size(ptr, layout);
+            self.allocations
+                .lock()
+                .insert(ptr as usize, layout, size, caller, tick);
+        }
+    }
 
-    pub fn init(&self, page_count: usize) {
-        self.page_allocator.init(page_count);
+    /// Bytes set aside for the allocation of `layout` at `ptr`, including headers and rounding
+    #[cfg(feature = "kalloc-track")]
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn reserved_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        if uses_page_allocator(layout.size(), layout.align()) {
+            KernelPageAllocator::order_for(layout)
+                .map_or(0, |order| (c_bindings::PGSIZE as usize) << order)
+        } else if let Some(cache) = self.slab_cache_for(layout.size()) {
+            cache.object_size()
+        } else {
+            let header = ptr.sub(REDZONE_SIZE).cast::<TinyHeader>().sub(1);
+            (*header).size + core::mem::size_of::<TinyHeader>()
+        }
+    }
+
+    /// Print every outstanding allocation, grouped by the code that made it
+    #[cfg_attr(not(feature = "kalloc-track"), allow(clippy::unused_self))]
+    pub(crate) fn dump_allocations(&self) {
+        if cfg!(feature = "kalloc-track") {
+            printf!(b"\nkalloc: outstanding allocations by call site\n\0");
+        } else {
+            printf!(b"\nkalloc: allocation tracking needs the kalloc-track feature\n\0");
+        }
+        #[cfg(feature = "kalloc-track")]
+        self.allocations.lock().dump();
+    }
+
+    /// The slab cache for allocations of `size` bytes, or `None` if too big for any size class
+    /// Debug builds send every small allocation through the tiny list, so it gets redzones
+    fn slab_cache_for(&self, size: usize) -> Option<&SlabCache<'_>> {
+        if cfg!(feature = "kalloc-debug") {
+            return None;
+        }
+        self.slab_caches
+            .iter()
+            .find(|cache| cache.object_size() >= size)
     }
 
     pub(crate) fn memfree_count(&self) -> u64 {
@@ -384,7 +995,12 @@ impl KernelAllocator<'_> {
             }
             u64::try_from(tiny_space).unwrap()
         };
-        tiny_space + self.page_allocator.pfree_count()
+        let slab_space = self
+            .slab_caches
+            .iter()
+            .map(|cache| u64::try_from(cache.free_bytes()).unwrap())
+            .sum::<u64>();
+        tiny_space + slab_space + self.page_allocator.pfree_count()
     }
 
     #[allow(clippy::cast_possible_truncation)]
@@ -413,6 +1029,8 @@ impl KernelAllocator<'_> {
                 *new_header = TinyHeader {
                     next: Cell::new(None),
                     size,
+                    #[cfg(feature = "kalloc-debug")]
+                    caller: 0,
                 };
             }
             unsafe { new_header.add(1).cast() }
@@ -426,6 +1044,182 @@ impl KernelAllocator<'_> {
         }
     }
 
+    /// Carve a `size` byte block out of a fresh page, putting the rest of the page on the tiny list
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn alloc_tiny_page(
+        &self,
+        tiny_list: &SpintexGuard<'_, '_, Cell<Option<NonNull<TinyHeader>>>>,
+        size: usize,
+    ) -> *mut u8 {
+        let page_layout = Layout::from_size_align_unchecked(
+            c_bindings::PGSIZE as usize,
+            c_bindings::PGSIZE as usize,
+        );
+        let new_page = self.page_allocator.alloc(page_layout);
+        if new_page.is_null() {
+            return new_page;
+        }
+        if cfg!(feature = "kalloc-debug") {
+            ptr::write_bytes(new_page, FREE_POISON, c_bindings::PGSIZE as usize);
+        }
+        let free_header = new_page
+            .add(size + core::mem::size_of::<TinyHeader>())
+            .cast::<TinyHeader>();
+        *free_header = TinyHeader {
+            next: Cell::new(None),
+            size: c_bindings::PGSIZE as usize - (size + 2 * core::mem::size_of::<TinyHeader>()),
+            #[cfg(feature = "kalloc-debug")]
+            caller: 0,
+        };
+        *new_page.cast::<TinyHeader>() = TinyHeader {
+            next: Cell::new(None),
+            size,
+            #[cfg(feature = "kalloc-debug")]
+            caller: 0,
+        };
+        self.release_tiny_block(tiny_list, NonNull::new_unchecked(free_header));
+        new_page.cast::<TinyHeader>().add(1).cast()
+    }
+
+    /// Insert a free block into the address-ordered tiny list, merging it with free neighbours
+    /// in the same page. Pages that become entirely free go back to the page allocator
+    unsafe fn release_tiny_block(
+        &self,
+        tiny_list: &SpintexGuard<'_, '_, Cell<Option<NonNull<TinyHeader>>>>,
+        block: NonNull<TinyHeader>,
+    ) {
+        // `before_prev` trails `prev` so a block merged into `prev` can still be unlinked
+        let mut before_prev: Option<NonNull<TinyHeader>> = None;
+        let mut prev: Option<NonNull<TinyHeader>> = None;
+        let mut next = tiny_list.get();
+        while let Some(next_block) = next {
+            if next_block >= block {
+                break;
+            }
+            before_prev = prev;
+            prev = next;
+            next = next_block.as_ref().next.get();
+        }
+        if next == Some(block) {
+            panic!("KTA_dealloc: Double free\0");
+        }
+
+        block.as_ref().next.set(next);
+        match prev {
+            Some(prev) => prev.as_ref().next.set(Some(block)),
+            None => tiny_list.set(Some(block)),
+        }
+
+        let mut block = block;
+        if let Some(next_block) = next {
+            if Self::tiny_blocks_adjacent(block, next_block) {
+                block.as_mut().size +=
+                    core::mem::size_of::<TinyHeader>() + next_block.as_ref().size;
+                block.as_ref().next.set(next_block.as_ref().next.get());
+                if cfg!(feature = "kalloc-debug") {
+                    Self::poison_tiny_header(next_block);
+                }
+            }
+        }
+        if let Some(mut prev_block) = prev {
+            if Self::tiny_blocks_adjacent(prev_block, block) {
+                prev_block.as_mut().size +=
+                    core::mem::size_of::<TinyHeader>() + block.as_ref().size;
+                prev_block.as_ref().next.set(block.as_ref().next.get());
+                if cfg!(feature = "kalloc-debug") {
+                    Self::poison_tiny_header(block);
+                }
+                block = prev_block;
+                prev = before_prev;
+            }
+        }
+
+        // The whole page is free again, so give it back
+        if block.as_ptr() as usize % c_bindings::PGSIZE as usize == 0
+            && block.as_ref().size + core::mem::size_of::<TinyHeader>()
+                == c_bindings::PGSIZE as usize
+        {
+            match prev {
+                Some(prev) => prev.as_ref().next.set(block.as_ref().next.get()),
+                None => tiny_list.set(block.as_ref().next.get()),
+            }
+            let page_layout = Layout::from_size_align_unchecked(
+                c_bindings::PGSIZE as usize,
+                c_bindings::PGSIZE as usize,
+            );
+            self.page_allocator
+                .dealloc(block.as_ptr().cast(), page_layout);
+        }
+    }
+
+    /// Poison the header of a tiny block that was merged into the free block before it
+    unsafe fn poison_tiny_header(block: NonNull<TinyHeader>) {
+        ptr::write_bytes(
+            block.as_ptr().cast::<u8>(),
+            FREE_POISON,
+            core::mem::size_of::<TinyHeader>(),
+        );
+    }
+
+    /// Check a tiny block is untouched since it was freed, then fence the `size` bytes handed
+    /// out with redzones and record `caller` in its header
+    #[cfg(feature = "kalloc-debug")]
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn guard_tiny_block(data: *mut u8, size: usize, caller: u64) {
+        let header = data.cast::<TinyHeader>().sub(1);
+        let block_size = (*header).size;
+        let poisoned = core::slice::from_raw_parts(data, block_size);
+        if let Some(offset) = poisoned.iter().position(|byte| *byte != FREE_POISON) {
+            printf!(
+                b"kalloc: free block %p modified at %p\n\0",
+                data.add(REDZONE_SIZE),
+                data.add(offset)
+            );
+            panic!("kalloc: heap corruption\0");
+        }
+        (*header).caller = caller;
+        ptr::write_bytes(data, REDZONE_POISON, REDZONE_SIZE);
+        ptr::write_bytes(data.add(REDZONE_SIZE), ALLOC_POISON, size);
+        ptr::write_bytes(
+            data.add(REDZONE_SIZE + size),
+            REDZONE_POISON,
+            block_size - REDZONE_SIZE - size,
+        );
+    }
+
+    /// Panic if anything wrote past either end of the `size` bytes handed out from a tiny block
+    #[cfg(feature = "kalloc-debug")]
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn check_tiny_redzones(data: *mut u8, size: usize) {
+        let header = data.cast::<TinyHeader>().sub(1);
+        let block_size = (*header).size;
+        let front = core::slice::from_raw_parts(data, REDZONE_SIZE);
+        let back = core::slice::from_raw_parts(
+            data.add(REDZONE_SIZE + size),
+            block_size - REDZONE_SIZE - size,
+        );
+        if front
+            .iter()
+            .chain(back.iter())
+            .any(|byte| *byte != REDZONE_POISON)
+        {
+            printf!(
+                b"kalloc: redzone of %p overwritten, allocated by %p\n\0",
+                data.add(REDZONE_SIZE),
+                (*header).caller
+            );
+            panic!("kalloc: heap corruption\0");
+        }
+    }
+
+    /// Whether `second` starts right where `first` ends, inside the same page
+    fn tiny_blocks_adjacent(first: NonNull<TinyHeader>, second: NonNull<TinyHeader>) -> bool {
+        let first_end = first.as_ptr() as usize
+            + core::mem::size_of::<TinyHeader>()
+            + unsafe { first.as_ref() }.size;
+        first_end == second.as_ptr() as usize && first_end % c_bindings::PGSIZE as usize != 0
+    }
+
     fn default_realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
         let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
         // SAFETY: the caller must ensure that `new_layout` is greater than zero.
@@ -442,6 +1236,33 @@ impl KernelAllocator<'_> {
     }
 }
 
+/// Return address of the code that called into the heap, or 0 if allocation sites are not recorded
+#[inline(never)]
+fn allocation_site() -> u64 {
+    if cfg!(any(feature = "kalloc-debug", feature = "kalloc-track")) {
+        return_address(ALLOC_CALLER_DEPTH)
+    } else {
+        0
+    }
+}
+
+/// Return address `depth` frames above the caller of this function, or 0 past the top of the stack
+/// Relies on the existence of frame pointers!
+#[inline(never)]
+fn return_address(depth: usize) -> u64 {
+    let mut fp = crate::riscv_asm::r_fp!();
+    let max_stack_addr = crate::riscv_asm::page_round_down!(fp) + u64::from(c_bindings::PGSIZE);
+    let mut return_addr = 0;
+    for _ in 0..=depth {
+        if fp >= max_stack_addr {
+            return 0;
+        }
+        return_addr = unsafe { *(fp as *const u64).offset(-1) };
+        fp = unsafe { *(fp as *const u64).offset(-2) };
+    }
+    return_addr
+}
+
 /// C Entry point for Kernel Page Alloc
 #[no_mangle]
 #[allow(clippy::missing_safety_doc)]
@@ -451,6 +1272,21 @@ pub unsafe extern "C" fn kalloc() -> *mut core::ffi::c_void {
     alloc::alloc::alloc(layout).cast()
 }
 
+/// C Entry point for Kernel Page Alloc, of a page filled with zeros
+#[no_mangle]
+#[allow(clippy::missing_safety_doc)]
+pub unsafe extern "C" fn kalloc_zeroed() -> *mut core::ffi::c_void {
+    let layout =
+        Layout::from_size_align_unchecked(c_bindings::PGSIZE as usize, c_bindings::PGSIZE as usize);
+    alloc::alloc::alloc_zeroed(layout).cast()
+}
+
+/// Called by the scheduler when it has nothing to run, to zero pages ahead of time
+#[no_mangle]
+pub extern "C" fn kzero_refill() {
+    ALLOCATOR.refill_zeroed_pages();
+}
+
 /// C Entry point for Kernel Page Frees
 /// # Safety
 /// `ptr` should be allocated from `kalloc` and should be page-aligned
@@ -13,27 +13,46 @@ pub mod c_bindings {
     include!(concat!(env!("OUT_DIR"), "/kernel_bindings.rs"));
 }
 
+/// Table of live kernel allocations, for finding leaks
+#[cfg(feature = "kalloc-track")]
+pub mod alloc_tracker;
+/// Address space identifiers, which tag the TLB entries of each process
+pub mod asid;
 /// Device specific code, for loading FDTs and
 /// communicating with devices
 pub mod dev;
 /// Exec syscall implementation details
 pub mod exec;
+/// Counts of page faults, per process and system-wide
+pub mod fault_stats;
 /// Interrupt handling
 pub mod interrupts;
 /// Kernel page allocations
 pub mod kalloc;
+/// Memory mapped regions, created by mmap
+pub mod mmap;
+/// Memory pressure tracking and the OOM killer
+pub mod oom;
 /// Functions around printing to the screen
 pub mod printf;
 /// Process management
 pub mod proc;
 /// Macros for interfacing with riscv assembly
 pub mod riscv_asm;
+/// Shared memory segments that processes map by key
+pub mod shm;
+/// Slab caches for small, fixed-size kernel allocations
+pub mod slab;
+/// Swapping pages out to the swap area on disk when memory runs low
+pub mod swap;
 /// Kernel Sycronization primatives
 pub mod sync;
 /// rv6 syscall implementations
 pub mod syscall;
 /// rv6 trap handlers
 pub mod trap;
+/// Checked copies to and from user memory
+pub mod uaccess;
 /// rv6 Virtual Memory routines
 pub mod vm;
 
@@ -0,0 +1,409 @@
+use core::alloc::Layout;
+
+use crate::asid::{flush_tlb_page, proc_flush_tlb};
+use crate::c_bindings;
+use crate::interrupts::holding_spinlocks;
+use crate::oom::{add_resident, remove_resident};
+use crate::vm::{copy_pages, uvmunmap, LazyAllocError, PageTableEntry, PGROUNDDOWN, PGROUNDUP};
+
+/// Map `len` bytes of `file` starting at `offset` into `proc`, or anonymous memory if `file` is null.
+/// Pages are only read in when first accessed.
+/// Returns the start of the new region, or None if the arguments are invalid or there is no room
+/// # Safety
+/// `file` must be null, or one of `proc`'s open files
+pub(crate) unsafe fn mmap(
+    proc: &mut c_bindings::proc_,
+    len: u64,
+    protection: i32,
+    flags: i32,
+    file: *mut c_bindings::file,
+    offset: u64,
+) -> Option<u64> {
+    let len = PGROUNDUP!(len);
+    let shared = has_flag(flags, c_bindings::MAP_SHARED);
+    // File offsets are 32 bits
+    if len == 0
+        || offset % u64::from(c_bindings::PGSIZE) != 0
+        || offset
+            .checked_add(len)
+            .map_or(true, |end| end > u64::from(u32::MAX))
+        || shared == has_flag(flags, c_bindings::MAP_PRIVATE)
+    {
+        return None;
+    }
+
+    let file = if has_flag(flags, c_bindings::MAP_ANONYMOUS) {
+        core::ptr::null_mut()
+    } else {
+        let backing_file = unsafe { file.as_ref() }?;
+        // Pages are read in from the file even if they are only written to
+        if backing_file.type_ != c_bindings::file__bindgen_ty_1::FD_INODE
+            || backing_file.readable == 0
+            || (shared
+                && has_flag(protection, c_bindings::PROT_WRITE)
+                && backing_file.writable == 0)
+        {
+            return None;
+        }
+        file
+    };
+
+    let slot = proc.vmas.iter().position(|vma| vma.len == 0)?;
+    let addr = free_range(proc, len)?;
+    if !file.is_null() {
+        unsafe { c_bindings::filedup(file) };
+    }
+    proc.vmas[slot] = c_bindings::vma {
+        addr,
+        len,
+        prot: protection,
+        flags,
+        file,
+        off: offset,
+        shm: 0,
+    };
+    Some(addr)
+}
+
+/// Unmap the pages of `proc` in `[addr, addr + len)`, writing dirty pages of shared file mappings
+/// back to the file. The range must be at the start or end of a single mapped region.
+/// Returns None if the range is not mapped that way
+/// # Safety
+/// Must be called without any locks held, as writing back pages sleeps
+pub(crate) unsafe fn munmap(proc: &mut c_bindings::proc_, addr: u64, len: u64) -> Option<()> {
+    let end = addr.checked_add(PGROUNDUP!(len))?;
+    if addr % u64::from(c_bindings::PGSIZE) != 0 || len == 0 {
+        return None;
+    }
+    let slot = proc
+        .vmas
+        .iter()
+        .position(|vma| vma.len > 0 && vma.addr <= addr && end <= vma.addr + vma.len)?;
+    let vma = proc.vmas[slot];
+    // Punching a hole would split the region in two
+    if addr != vma.addr && end != vma.addr + vma.len {
+        return None;
+    }
+    unsafe { unmap_range(proc, slot, addr..end, true) };
+    Some(())
+}
+
+/// Populate the page of a mapped region containing `va`, reading it in from the backing file.
+/// `write` is set for stores.
+/// Fails with `NotLazy` for a file mapping if this CPU holds spinlocks, as reading the file sleeps
+/// # Safety
+/// Assumes that `proc` has a valid page table
+pub(crate) unsafe fn fault(
+    proc: &mut c_bindings::proc_,
+    va: u64,
+    write: bool,
+) -> Result<(), LazyAllocError> {
+    let pagetable = proc.pagetable;
+    let vma = proc
+        .vmas
+        .iter()
+        .find(|vma| vma.len > 0 && vma.addr <= va && va < vma.addr + vma.len)
+        .ok_or(LazyAllocError::NotLazy)?;
+    if !protection_allows(vma.prot, write) || (!vma.file.is_null() && holding_spinlocks()) {
+        return Err(LazyAllocError::NotLazy);
+    }
+
+    let va0 = PGROUNDDOWN!(va);
+    if unsafe {
+        c_bindings::walk(pagetable, va0, 0)
+            .cast::<PageTableEntry>()
+            .as_ref()
+    }
+    .is_some_and(PageTableEntry::valid)
+    {
+        return Err(LazyAllocError::NotLazy);
+    }
+
+    let offset = vma
+        .off
+        .checked_add(va0 - vma.addr)
+        .and_then(|offset| u32::try_from(offset).ok())
+        .ok_or(LazyAllocError::NotLazy)?;
+    let page_size = c_bindings::PGSIZE as usize;
+    let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+    let page = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if page.is_null() {
+        return Err(LazyAllocError::OutOfMemory);
+    }
+    if let Some(file) = unsafe { vma.file.as_ref() } {
+        // Whatever is past the end of the file stays zeroed
+        unsafe {
+            c_bindings::ilock(file.ip);
+            c_bindings::readi(file.ip, 0, page as u64, offset, c_bindings::PGSIZE);
+            c_bindings::iunlock(file.ip);
+        }
+    }
+
+    let perm = c_bindings::PTE_U | pte_permissions(vma.prot);
+    if unsafe {
+        c_bindings::mappages(
+            pagetable,
+            va0,
+            u64::from(c_bindings::PGSIZE),
+            page as u64,
+            i32::try_from(perm).unwrap(),
+        )
+    } != 0
+    {
+        unsafe { alloc::alloc::dealloc(page, layout) };
+        return Err(LazyAllocError::OutOfMemory);
+    }
+    add_resident(proc, 1);
+    Ok(())
+}
+
+/// Read in the pages of file mappings in `range` that haven't been faulted in yet.
+/// Pages already mapped, or that their region's protection doesn't allow reading, are skipped.
+/// Returns whether any page was read in
+/// # Safety
+/// Sleeps to read the files, so must be called without any spinlocks held
+pub(crate) unsafe fn fault_in(proc: &mut c_bindings::proc_, range: core::ops::Range<u64>) -> bool {
+    let mut faulted_in = false;
+    for slot in 0..proc.vmas.len() {
+        let vma = proc.vmas[slot];
+        if vma.len == 0 || vma.file.is_null() {
+            continue;
+        }
+        let start = PGROUNDDOWN!(range.start.max(vma.addr));
+        let end = range.end.min(vma.addr + vma.len);
+        for va in (start..end).step_by(c_bindings::PGSIZE as usize) {
+            match unsafe { fault(proc, va, false) } {
+                Ok(()) => {
+                    flush_tlb_page(proc, va);
+                    faulted_in = true;
+                }
+                Err(LazyAllocError::OutOfMemory) => return faulted_in,
+                Err(_) => {}
+            }
+        }
+    }
+    faulted_in
+}
+
+/// Change the protection of the mapped region covering exactly `range` to `protection`,
+/// which pages faulted in from then on get.
+/// Returns whether the region is shared, or None if no region covers exactly `range`,
+/// the region is a shared memory segment, or a shared file mapping would become writeable
+/// while its file is not
+pub(crate) fn protect(
+    proc: &mut c_bindings::proc_,
+    range: core::ops::Range<u64>,
+    protection: i32,
+) -> Option<bool> {
+    let vma = proc
+        .vmas
+        .iter_mut()
+        .find(|vma| vma.len > 0 && vma.addr == range.start && vma.addr + vma.len == range.end)?;
+    let shared = has_flag(vma.flags, c_bindings::MAP_SHARED);
+    if vma.shm != 0
+        || (shared
+            && has_flag(protection, c_bindings::PROT_WRITE)
+            && unsafe { vma.file.as_ref() }.is_some_and(|file| file.writable == 0))
+    {
+        return None;
+    }
+    vma.prot = protection;
+    Some(shared)
+}
+
+/// Give `child` the mapped regions of `parent`. Pages of private mappings are shared COW,
+/// while shared mappings keep writing to the same pages.
+/// Shared anonymous pages have nowhere else to be shared through, so they are all populated first.
+/// returns 0 on success, -1 on failure, leaving `child` with no mapped regions.
+/// # Safety
+/// Assumes that both processes have valid page tables
+#[no_mangle]
+pub unsafe extern "C" fn mmap_copy(
+    parent: *mut c_bindings::proc_,
+    child: *mut c_bindings::proc_,
+) -> core::ffi::c_int {
+    let (parent, child) = unsafe { (&mut *parent, &mut *child) };
+    for slot in 0..parent.vmas.len() {
+        let vma = parent.vmas[slot];
+        if vma.len == 0 {
+            continue;
+        }
+        if !vma.file.is_null() {
+            unsafe { c_bindings::filedup(vma.file) };
+        }
+        if vma.shm != 0 {
+            crate::shm::dup(vma.shm);
+        }
+        child.vmas[slot] = vma;
+        let shared = has_flag(vma.flags, c_bindings::MAP_SHARED);
+        let populated = !shared
+            || !vma.file.is_null()
+            || (vma.addr..vma.addr + vma.len)
+                .step_by(c_bindings::PGSIZE as usize)
+                .all(|va| {
+                    !matches!(
+                        unsafe { fault(parent, va, false) },
+                        Err(LazyAllocError::OutOfMemory)
+                    )
+                });
+        if !populated
+            || unsafe {
+                copy_pages(
+                    parent.pagetable,
+                    child.pagetable,
+                    vma.addr..vma.addr + vma.len,
+                    shared,
+                )
+            } != 0
+        {
+            // The parent still holds every page and file, so nothing needs writing back
+            for slot in 0..child.vmas.len() {
+                let vma = child.vmas[slot];
+                if vma.len > 0 {
+                    unsafe { unmap_range(child, slot, vma.addr..vma.addr + vma.len, false) };
+                }
+            }
+            return -1;
+        }
+    }
+    0
+}
+
+/// Unmap every mapped region of `proc`, writing back dirty shared pages
+/// # Safety
+/// Must be called without any locks held, as writing back pages sleeps
+#[no_mangle]
+pub unsafe extern "C" fn munmap_all(proc: *mut c_bindings::proc_) {
+    let proc = unsafe { &mut *proc };
+    for slot in 0..proc.vmas.len() {
+        let vma = proc.vmas[slot];
+        if vma.len > 0 {
+            unsafe { unmap_range(proc, slot, vma.addr..vma.addr + vma.len, true) };
+        }
+    }
+}
+
+/// Unmap `range` from the region in `slot`, which must be at its start or end,
+/// and shrink the region to what is left
+pub(crate) unsafe fn unmap_range(
+    proc: &mut c_bindings::proc_,
+    slot: usize,
+    range: core::ops::Range<u64>,
+    write_back: bool,
+) {
+    let vma = proc.vmas[slot];
+    if write_back && has_flag(vma.flags, c_bindings::MAP_SHARED) && !vma.file.is_null() {
+        for va in range.clone().step_by(c_bindings::PGSIZE as usize) {
+            let pte = unsafe {
+                c_bindings::walk(proc.pagetable, va, 0)
+                    .cast::<PageTableEntry>()
+                    .as_ref()
+            };
+            if let Some(pte) = pte.filter(|pte| pte.valid() && pte.dirty()) {
+                unsafe { write_back_page(&vma, va, pte.pa_int()) };
+            }
+        }
+    }
+    let unmapped = unsafe {
+        uvmunmap(
+            proc.pagetable,
+            range.start,
+            (range.end - range.start) / u64::from(c_bindings::PGSIZE),
+            1,
+        )
+    };
+    remove_resident(proc, unmapped);
+    unsafe { proc_flush_tlb(proc) };
+
+    let vma = &mut proc.vmas[slot];
+    if range.start == vma.addr && range.end == vma.addr + vma.len {
+        if !vma.file.is_null() {
+            unsafe { c_bindings::fileclose(vma.file) };
+        }
+        if vma.shm != 0 {
+            crate::shm::detach(vma.shm);
+        }
+        vma.len = 0;
+        vma.file = core::ptr::null_mut();
+        vma.shm = 0;
+    } else if range.start == vma.addr {
+        vma.addr = range.end;
+        vma.off += range.end - range.start;
+        vma.len -= range.end - range.start;
+    } else {
+        vma.len = range.start - vma.addr;
+    }
+}
+
+/// Write the page at physical address `pa`, mapped at `va`, back to the file behind `vma`.
+/// Never writes past the end of the file
+unsafe fn write_back_page(vma: &c_bindings::vma, va: u64, pa: u64) {
+    let ip = unsafe { (*vma.file).ip };
+    let offset = vma.off + (va - vma.addr);
+    unsafe {
+        c_bindings::begin_op();
+        c_bindings::ilock(ip);
+        let size = u64::from((*ip).size);
+        if offset < size {
+            let length = (size - offset).min(u64::from(c_bindings::PGSIZE));
+            c_bindings::writei(
+                ip,
+                0,
+                pa,
+                u32::try_from(offset).unwrap(),
+                u32::try_from(length).unwrap(),
+            );
+        }
+        c_bindings::iunlock(ip);
+        c_bindings::end_op();
+    }
+}
+
+/// The highest free range of `len` bytes between the heap and the USYSCALL page
+pub(crate) fn free_range(proc: &c_bindings::proc_, len: u64) -> Option<u64> {
+    let mut top = c_bindings::USYSCALL;
+    loop {
+        let start = top.checked_sub(len)?;
+        if start < PGROUNDUP!(proc.sz) {
+            return None;
+        }
+        match proc
+            .vmas
+            .iter()
+            .filter(|vma| vma.len > 0 && vma.addr < top && start < vma.addr + vma.len)
+            .map(|vma| vma.addr)
+            .min()
+        {
+            None => return Some(start),
+            Some(overlapping_start) => top = overlapping_start,
+        }
+    }
+}
+
+/// The `PTE_R`, `PTE_W` and `PTE_X` bits of pages with protection `protection`,
+/// made of the `PROT_` flags
+pub(crate) fn pte_permissions(protection: i32) -> u32 {
+    let mut perm = 0;
+    // RISC-V has no write-only pages, so PROT_WRITE implies PROT_READ
+    if has_flag(protection, c_bindings::PROT_WRITE) {
+        perm |= c_bindings::PTE_R | c_bindings::PTE_W;
+    }
+    if has_flag(protection, c_bindings::PROT_READ) {
+        perm |= c_bindings::PTE_R;
+    }
+    if has_flag(protection, c_bindings::PROT_EXEC) {
+        perm |= c_bindings::PTE_X;
+    }
+    perm
+}
+
+/// Whether pages with protection `protection` can be accessed at all, and written if `write` is set
+pub(crate) fn protection_allows(protection: i32, write: bool) -> bool {
+    let perm = pte_permissions(protection);
+    perm != 0 && (!write || perm & c_bindings::PTE_W != 0)
+}
+
+/// Whether `flag`, one of the `PROT_` or `MAP_` constants, is set in `bits`
+pub(crate) fn has_flag(bits: i32, flag: u32) -> bool {
+    bits & i32::try_from(flag).unwrap() != 0
+}
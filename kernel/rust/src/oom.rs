@@ -0,0 +1,152 @@
+use crate::c_bindings;
+use crate::kalloc::ALLOCATOR;
+use crate::printf::printf;
+use crate::proc::sleep_rust;
+use crate::sync::spinlock::Spintex;
+use crate::trap::TICKS;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+/// Memory pressure is reported once fewer than 1/`LOW_WATERMARK_DIVISOR` of all pages are free
+const LOW_WATERMARK_DIVISOR: usize = 32;
+/// The OOM killer runs once fewer than 1/`MIN_WATERMARK_DIVISOR` of all pages are free
+const MIN_WATERMARK_DIVISOR: usize = 64;
+
+/// Whether free pages were below the low watermark when last checked
+static UNDER_PRESSURE: AtomicBool = AtomicBool::new(false);
+/// Held by the reclaimer from checking `UNDER_PRESSURE` until it sleeps, so it misses no wakeup
+static PRESSURE_WAIT: Spintex<'static, ()> = Spintex::new((), "pressure");
+/// The kernel thread running [`reclaimer`], which is never killed
+static RECLAIMER: AtomicPtr<c_bindings::proc_> = AtomicPtr::new(ptr::null_mut());
+
+/// Free physical pages, and the thresholds they are measured against
+pub(crate) struct Watermarks {
+    pub(crate) free: usize,
+    pub(crate) low: usize,
+    pub(crate) min: usize,
+}
+
+pub(crate) fn watermarks() -> Watermarks {
+    let total_pages = ALLOCATOR.total_pages();
+    Watermarks {
+        free: ALLOCATOR.free_pages(),
+        low: total_pages / LOW_WATERMARK_DIVISOR,
+        min: total_pages / MIN_WATERMARK_DIVISOR,
+    }
+}
+
+/// Log crossings of the low watermark, waking the reclaimer when free pages fall below it.
+/// Cheap enough for every timer tick, as the reclaimer does the swapping out and killing
+/// Must be called without any locks held
+pub(crate) fn check_pressure() {
+    let watermarks = watermarks();
+    let under_pressure = watermarks.free < watermarks.low;
+    if UNDER_PRESSURE.swap(under_pressure, Ordering::Relaxed) != under_pressure {
+        if under_pressure {
+            printf!(b"oom: low on memory, %d pages free\n\0", watermarks.free);
+            let _wait = PRESSURE_WAIT.lock();
+            unsafe { c_bindings::wakeup(NonNull::from(&PRESSURE_WAIT).as_ptr().cast()) };
+        } else {
+            printf!(
+                b"oom: memory pressure relieved, %d pages free\n\0",
+                watermarks.free
+            );
+        }
+    }
+}
+
+/// The body of the kernel thread that gets memory back while free pages are below the
+/// low watermark, swapping pages out, and killing a process if they fall below the minimum.
+/// Sleeps until [`check_pressure`] wakes it, and between batches of pages that don't
+/// get memory back, until the next timer tick
+#[no_mangle]
+pub extern "C" fn reclaimer() -> ! {
+    RECLAIMER.store(unsafe { c_bindings::myproc() }, Ordering::Relaxed);
+    loop {
+        let wait = PRESSURE_WAIT.lock();
+        if !UNDER_PRESSURE.load(Ordering::Relaxed) {
+            sleep_rust(NonNull::from(&PRESSURE_WAIT), wait);
+            continue;
+        }
+        drop(wait);
+
+        // Swapping pages out is tried before killing anything. Finding no pages to
+        // swap out doesn't mean there are none, as processes busy in the kernel can't
+        // be swapped from, so nothing is killed until the swap area is full
+        let watermarks = watermarks();
+        let reclaimed = watermarks.free < watermarks.low && crate::swap::reclaim();
+        if !reclaimed && watermarks.free < watermarks.min && crate::swap::full() {
+            out_of_memory();
+        }
+        if reclaimed {
+            unsafe { c_bindings::yield_() };
+        } else {
+            let ticks = TICKS.lock();
+            sleep_rust(NonNull::from(&TICKS), ticks);
+        }
+    }
+}
+
+/// Swap pages out, or once swap is full, kill the process with the most resident pages,
+/// to get memory back
+/// Does nothing if an earlier victim has yet to exit, as its memory is on the way
+/// Returns false if there was no process to kill
+/// Must be called without any locks held
+pub(crate) fn out_of_memory() -> bool {
+    if crate::swap::reclaim() {
+        return true;
+    }
+    let mut victim: Option<(i32, u64)> = None;
+    let reclaimer = RECLAIMER.load(Ordering::Relaxed);
+    for proc in unsafe { (*ptr::addr_of_mut!(c_bindings::proc_)).iter_mut() } {
+        unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+        // The reclaimer has no user memory to give back
+        let killable = !ptr::eq(proc, reclaimer)
+            && matches!(
+                proc.state,
+                c_bindings::procstate::SLEEPING
+                    | c_bindings::procstate::RUNNABLE
+                    | c_bindings::procstate::RUNNING
+            );
+        if killable && proc.killed != 0 {
+            unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+            return true;
+        }
+        // init can never be killed
+        if killable && proc.pid != 1 {
+            let resident = proc.resident;
+            if victim.map_or(true, |(_, most_resident)| resident > most_resident) {
+                victim = Some((proc.pid, resident));
+            }
+        }
+        unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+    }
+
+    let Some((pid, resident)) = victim else {
+        printf!(b"oom: out of memory, with no process to kill\n\0");
+        return false;
+    };
+    printf!(
+        b"oom: killing pid %d with %d resident pages, %d pages free\n\0",
+        pid,
+        resident,
+        ALLOCATOR.free_pages()
+    );
+    unsafe { c_bindings::kill(pid) };
+    true
+}
+
+/// Count `pages` more pages of `proc` as resident, when they are mapped in memory.
+/// A megapage counts as every page it covers
+pub(crate) fn add_resident(proc: &mut c_bindings::proc_, pages: u64) {
+    unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+    proc.resident += pages;
+    unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+}
+
+/// Count `pages` fewer pages of `proc` as resident, when they are unmapped or swapped out
+pub(crate) fn remove_resident(proc: &mut c_bindings::proc_, pages: u64) {
+    unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+    proc.resident -= pages;
+    unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+}
@@ -97,6 +97,29 @@ macro_rules! intr_get {
     }};
 }
 
+macro_rules! sfence_vma_asid {
+    ($asid:expr) => {
+        #[allow(unused_unsafe)]
+        unsafe {
+            let asid: u64 = $asid;
+            // flush the TLB entries of one address space.
+            core::arch::asm!("sfence.vma zero, {0}", in(reg) asid, options(nostack));
+        }
+    };
+}
+
+macro_rules! sfence_vma_page {
+    ($va:expr, $asid:expr) => {
+        #[allow(unused_unsafe)]
+        unsafe {
+            let va: u64 = $va;
+            let asid: u64 = $asid;
+            // flush the TLB entry of one page in one address space.
+            core::arch::asm!("sfence.vma {0}, {1}", in(reg) va, in(reg) asid, options(nostack));
+        }
+    };
+}
+
 macro_rules! page_round_down {
     ($address:expr) => {
         $address & !($crate::c_bindings::PGSIZE as u64 - 1)
@@ -112,5 +135,7 @@ pub(crate) use r_scause;
 pub(crate) use r_sepc;
 pub(crate) use r_sstatus;
 pub(crate) use r_stval;
+pub(crate) use sfence_vma_asid;
+pub(crate) use sfence_vma_page;
 pub(crate) use w_sstatus;
 pub(crate) use w_stvec;
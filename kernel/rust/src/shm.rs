@@ -0,0 +1,211 @@
+use core::alloc::Layout;
+
+use crate::asid::proc_flush_tlb;
+use crate::c_bindings;
+use crate::kalloc::ALLOCATOR;
+use crate::mmap::{free_range, unmap_range};
+use crate::oom::add_resident;
+use crate::sync::spinlock::Spintex;
+use crate::vm::{PageTableEntry, PGROUNDUP};
+
+/// The most pages a segment can have, as many as its page list has room for
+const SEGMENT_PAGES: usize = c_bindings::PGSIZE as usize / core::mem::size_of::<u64>();
+
+/// The shared memory segments of the system
+static SEGMENTS: Spintex<'static, [Segment; c_bindings::NSHM as usize]> =
+    Spintex::new([Segment::UNUSED; c_bindings::NSHM as usize], "shm");
+
+/// Physical pages that processes map by key. The segment holds a reference to each page,
+/// and every page table mapping it holds another
+#[derive(Clone, Copy)]
+struct Segment {
+    key: i32,
+    /// Number of pages in the segment, 0 if this slot is unused
+    pages: usize,
+    /// Physical address of the page listing the physical address of each page of the segment
+    page_list: u64,
+    /// Mapped regions attached to the segment. It is freed when the last one is unmapped
+    attached: usize,
+}
+
+impl Segment {
+    const UNUSED: Segment = Segment {
+        key: 0,
+        pages: 0,
+        page_list: 0,
+        attached: 0,
+    };
+
+    /// Create a segment of `size` bytes of zeroed pages.
+    /// Returns None if `size` is 0 or too large, or there is not enough memory
+    fn new(key: i32, size: u64) -> Option<Self> {
+        let page_size = c_bindings::PGSIZE as usize;
+        if size == 0 || size > u64::try_from(SEGMENT_PAGES * page_size).ok()? {
+            return None;
+        }
+        let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+        let page_list = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        if page_list.is_null() {
+            return None;
+        }
+        let segment = Segment {
+            key,
+            pages: usize::try_from(PGROUNDUP!(size)).ok()? / page_size,
+            page_list: page_list as u64,
+            attached: 0,
+        };
+        for pa in unsafe { segment.page_addresses() } {
+            let page = unsafe { alloc::alloc::alloc_zeroed(layout) };
+            if page.is_null() {
+                unsafe { segment.free() };
+                return None;
+            }
+            *pa = page as u64;
+        }
+        Some(segment)
+    }
+
+    /// The physical address of each page of the segment, 0 for pages not yet allocated
+    /// # Safety
+    /// The segment must be in use, and its page list not otherwise borrowed
+    unsafe fn page_addresses(&self) -> &'static mut [u64] {
+        unsafe { core::slice::from_raw_parts_mut(self.page_list as *mut u64, self.pages) }
+    }
+
+    /// Drop the segment's references to its pages, and free its page list
+    /// # Safety
+    /// The segment must be in use, and is unused afterwards
+    unsafe fn free(&self) {
+        let page_size = c_bindings::PGSIZE as usize;
+        let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+        for &pa in unsafe { self.page_addresses() }.iter() {
+            if pa != 0 {
+                unsafe { alloc::alloc::dealloc(pa as *mut u8, layout) };
+            }
+        }
+        unsafe { alloc::alloc::dealloc(self.page_list as *mut u8, layout) };
+    }
+}
+
+/// Attach to the segment with `key`, creating it with `size` bytes if there is none.
+/// Returns the segment, and its number for [`c_bindings::vma::shm`],
+/// or None if it is smaller than `size` or can't be created
+fn attach(key: i32, size: u64) -> Option<(Segment, i32)> {
+    let mut segments = SEGMENTS.lock();
+    let index = segments
+        .iter()
+        .position(|segment| segment.pages > 0 && segment.key == key)
+        .or_else(|| {
+            let index = segments.iter().position(|segment| segment.pages == 0)?;
+            segments[index] = Segment::new(key, size)?;
+            Some(index)
+        })?;
+    let segment = &mut segments[index];
+    if size > u64::try_from(segment.pages).ok()? * u64::from(c_bindings::PGSIZE) {
+        return None;
+    }
+    segment.attached += 1;
+    Some((*segment, i32::try_from(index + 1).ok()?))
+}
+
+/// Attach another mapped region to segment number `shm`, for a forked child
+pub(crate) fn dup(shm: i32) {
+    let mut segments = SEGMENTS.lock();
+    segments[usize::try_from(shm - 1).unwrap()].attached += 1;
+}
+
+/// Detach a mapped region from segment number `shm`, freeing the segment if it was the last.
+/// Pages still mapped elsewhere keep their own references
+pub(crate) fn detach(shm: i32) {
+    let mut segments = SEGMENTS.lock();
+    let segment = &mut segments[usize::try_from(shm - 1).unwrap()];
+    segment.attached -= 1;
+    if segment.attached == 0 {
+        unsafe { segment.free() };
+        *segment = Segment::UNUSED;
+    }
+}
+
+/// Map the segment with `key` into `proc`, creating a zeroed segment of `size` bytes if there
+/// is none. The whole segment is mapped readable and writeable, and writes go to the same pages
+/// in every process attached to it, including forked children.
+/// The segment is freed once no process is attached to it.
+/// Returns the start of the mapping, or None if the segment is smaller than `size`,
+/// or can't be created or mapped
+/// # Safety
+/// Assumes that `proc` has a valid page table
+pub(crate) unsafe fn shmat(proc: &mut c_bindings::proc_, key: i32, size: u64) -> Option<u64> {
+    let slot = proc.vmas.iter().position(|vma| vma.len == 0)?;
+    let (segment, shm) = attach(key, size)?;
+    let len = u64::try_from(segment.pages).ok()? * u64::from(c_bindings::PGSIZE);
+    let Some(addr) = free_range(proc, len) else {
+        detach(shm);
+        return None;
+    };
+    proc.vmas[slot] = c_bindings::vma {
+        addr,
+        len,
+        prot: i32::try_from(c_bindings::PROT_READ | c_bindings::PROT_WRITE).unwrap(),
+        flags: i32::try_from(c_bindings::MAP_SHARED | c_bindings::MAP_ANONYMOUS).unwrap(),
+        file: core::ptr::null_mut(),
+        off: 0,
+        shm,
+    };
+
+    // The segment isn't freed while this process is attached, so its page list stays put
+    let pages = unsafe { segment.page_addresses() };
+    for (va, &pa) in (addr..)
+        .step_by(c_bindings::PGSIZE as usize)
+        .zip(pages.iter())
+    {
+        if unsafe { map_page(proc.pagetable, va, pa) }.is_none() {
+            unsafe { unmap_range(proc, slot, addr..addr + len, false) };
+            return None;
+        }
+        add_resident(proc, 1);
+    }
+    unsafe { proc_flush_tlb(proc) };
+    Some(addr)
+}
+
+/// Unmap the whole of the segment mapped at `addr` from `proc`, detaching from it.
+/// Returns None if no segment is mapped there
+/// # Safety
+/// Assumes that `proc` has a valid page table
+pub(crate) unsafe fn shmdt(proc: &mut c_bindings::proc_, addr: u64) -> Option<()> {
+    let slot = proc
+        .vmas
+        .iter()
+        .position(|vma| vma.len > 0 && vma.shm != 0 && vma.addr == addr)?;
+    let vma = proc.vmas[slot];
+    unsafe { unmap_range(proc, slot, vma.addr..vma.addr + vma.len, false) };
+    Some(())
+}
+
+/// Map the segment page at `pa` at `va`, taking a reference to it for the page table
+/// # Safety
+/// Assumes that `pagetable` is a valid page table
+unsafe fn map_page(pagetable: c_bindings::pagetable_t, va: u64, pa: u64) -> Option<()> {
+    let perm = c_bindings::PTE_U | c_bindings::PTE_R | c_bindings::PTE_W;
+    if unsafe {
+        c_bindings::mappages(
+            pagetable,
+            va,
+            u64::from(c_bindings::PGSIZE),
+            pa,
+            i32::try_from(perm).unwrap(),
+        )
+    } != 0
+    {
+        return None;
+    }
+    ALLOCATOR.in_place_copy(usize::try_from(pa).ok()?);
+    // Marked so the page is never copied on write or swapped out
+    unsafe {
+        c_bindings::walk(pagetable, va, 0)
+            .cast::<PageTableEntry>()
+            .as_mut()
+    }?
+    .set_shared_segment();
+    Some(())
+}
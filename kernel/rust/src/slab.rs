@@ -0,0 +1,237 @@
+use crate::c_bindings;
+use crate::kalloc::KernelPageAllocator;
+use crate::printf::panic;
+use crate::sync::spinlock::Spintex;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
+
+const SLAB_LOCK_NAME: &str = "kmem_slab";
+
+/// Object sizes served by the slab caches, smallest first
+pub(crate) const SIZE_CLASSES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+/// The minimum number of objects a slab should be able to hold
+const MIN_OBJECTS_PER_SLAB: usize = 8;
+/// Empty slabs kept around per cache before they are handed back to the page allocator
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// Bookkeeping at the start of every slab
+#[repr(C, align(16))]
+struct Slab {
+    next: Option<NonNull<Slab>>,
+    prev: Option<NonNull<Slab>>,
+    free_objects: Option<NonNull<FreeObject>>,
+    in_use: usize,
+}
+
+/// A free object, linked into its slab's free list
+#[repr(C)]
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+/// The slabs of a cache, sorted by how many of their objects are in use
+struct SlabLists {
+    partial: Option<NonNull<Slab>>,
+    full: Option<NonNull<Slab>>,
+    empty: Option<NonNull<Slab>>,
+    empty_count: usize,
+}
+
+/// Bytes in each slab of `object_size` objects: enough pages for the [`Slab`] header
+/// and [`MIN_OBJECTS_PER_SLAB`] objects, rounded up to a power of two
+const fn slab_size(object_size: usize) -> usize {
+    let pages = (core::mem::size_of::<Slab>() + object_size * MIN_OBJECTS_PER_SLAB)
+        .div_ceil(c_bindings::PGSIZE as usize)
+        .next_power_of_two();
+    pages * c_bindings::PGSIZE as usize
+}
+
+const fn objects_per_slab(object_size: usize) -> usize {
+    (slab_size(object_size) - core::mem::size_of::<Slab>()) / object_size
+}
+
+// Every size class gets at least MIN_OBJECTS_PER_SLAB objects per slab
+const _: () = {
+    let mut class = 0;
+    while class < SIZE_CLASSES.len() {
+        assert!(objects_per_slab(SIZE_CLASSES[class]) >= MIN_OBJECTS_PER_SLAB);
+        class += 1;
+    }
+};
+
+/// A cache of same-sized objects, carved out of slabs of contiguous pages
+pub(crate) struct SlabCache<'a> {
+    object_size: usize,
+    slabs: Spintex<'a, SlabLists>,
+}
+
+unsafe impl Sync for SlabCache<'_> {}
+unsafe impl Send for SlabCache<'_> {}
+
+impl<'a> SlabCache<'a> {
+    pub(crate) const fn new(object_size: usize) -> Self {
+        Self {
+            object_size,
+            slabs: Spintex::new(
+                SlabLists {
+                    partial: None,
+                    full: None,
+                    empty: None,
+                    empty_count: 0,
+                },
+                SLAB_LOCK_NAME,
+            ),
+        }
+    }
+
+    /// The size of the objects in this cache
+    pub(crate) fn object_size(&self) -> usize {
+        self.object_size
+    }
+
+    /// Allocate one object, growing the cache by a slab from `page_allocator` if every slab is full
+    /// Returns null if out of memory
+    pub(crate) unsafe fn alloc(&self, page_allocator: &KernelPageAllocator) -> *mut u8 {
+        let mut slabs = self.slabs.lock();
+        let slab = match (slabs.partial, slabs.empty) {
+            (Some(slab), _) => slab,
+            (None, Some(slab)) => {
+                Self::remove(&mut slabs.empty, slab);
+                slabs.empty_count -= 1;
+                Self::push(&mut slabs.partial, slab);
+                slab
+            }
+            (None, None) => match self.new_slab(page_allocator) {
+                None => return ptr::null_mut(),
+                Some(slab) => {
+                    Self::push(&mut slabs.partial, slab);
+                    slab
+                }
+            },
+        };
+
+        let slab_ref = &mut *slab.as_ptr();
+        let object = slab_ref.free_objects.unwrap();
+        slab_ref.free_objects = object.as_ref().next;
+        slab_ref.in_use += 1;
+        if slab_ref.free_objects.is_none() {
+            Self::remove(&mut slabs.partial, slab);
+            Self::push(&mut slabs.full, slab);
+        }
+        object.as_ptr().cast()
+    }
+
+    /// Free an object allocated from this cache
+    /// Empty slabs beyond [`MAX_EMPTY_SLABS`] are returned to `page_allocator`
+    #[allow(clippy::cast_ptr_alignment)]
+    pub(crate) unsafe fn dealloc(&self, ptr: *mut u8, page_allocator: &KernelPageAllocator) {
+        let slab_size = self.slab_layout().size();
+        let slab = NonNull::new_unchecked((ptr as usize & !(slab_size - 1)) as *mut Slab);
+        let mut slabs = self.slabs.lock();
+        let slab_ref = &mut *slab.as_ptr();
+        if slab_ref.in_use == 0 {
+            panic!("slab_dealloc: slab is empty\0");
+        }
+
+        let was_full = slab_ref.free_objects.is_none();
+        let object = ptr.cast::<FreeObject>();
+        object.write(FreeObject {
+            next: slab_ref.free_objects,
+        });
+        slab_ref.free_objects = Some(NonNull::new_unchecked(object));
+        slab_ref.in_use -= 1;
+
+        if slab_ref.in_use == 0 {
+            Self::remove(
+                if was_full {
+                    &mut slabs.full
+                } else {
+                    &mut slabs.partial
+                },
+                slab,
+            );
+            if slabs.empty_count < MAX_EMPTY_SLABS {
+                Self::push(&mut slabs.empty, slab);
+                slabs.empty_count += 1;
+            } else {
+                Spintex::unlock(slabs);
+                page_allocator.dealloc(slab.as_ptr().cast(), self.slab_layout());
+            }
+        } else if was_full {
+            Self::remove(&mut slabs.full, slab);
+            Self::push(&mut slabs.partial, slab);
+        }
+    }
+
+    /// Bytes of free objects held by this cache
+    pub(crate) fn free_bytes(&self) -> usize {
+        let slabs = self.slabs.lock();
+        let capacity = self.objects_per_slab();
+        let mut free_objects = slabs.empty_count * capacity;
+        let mut slab = slabs.partial;
+        while let Some(slab_ptr) = slab {
+            let slab_ref = unsafe { slab_ptr.as_ref() };
+            free_objects += capacity - slab_ref.in_use;
+            slab = slab_ref.next;
+        }
+        free_objects * self.object_size
+    }
+
+    /// The size and alignment of each slab: see [`slab_size`]
+    fn slab_layout(&self) -> Layout {
+        let size = slab_size(self.object_size);
+        unsafe { Layout::from_size_align_unchecked(size, size) }
+    }
+
+    /// Offset of the first object in a slab, after the [`Slab`] header
+    fn first_object_offset() -> usize {
+        core::mem::size_of::<Slab>()
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        objects_per_slab(self.object_size)
+    }
+
+    /// Allocate and carve up a new slab, with every object free
+    #[allow(clippy::cast_ptr_alignment)]
+    unsafe fn new_slab(&self, page_allocator: &KernelPageAllocator) -> Option<NonNull<Slab>> {
+        let slab = NonNull::new(page_allocator.alloc(self.slab_layout()).cast::<Slab>())?;
+        let objects = slab.as_ptr().cast::<u8>().add(Self::first_object_offset());
+        let mut free_objects = None;
+        for index in (0..self.objects_per_slab()).rev() {
+            let object = objects.add(index * self.object_size).cast::<FreeObject>();
+            object.write(FreeObject { next: free_objects });
+            free_objects = Some(NonNull::new_unchecked(object));
+        }
+        slab.as_ptr().write(Slab {
+            next: None,
+            prev: None,
+            free_objects,
+            in_use: 0,
+        });
+        Some(slab)
+    }
+
+    unsafe fn push(list: &mut Option<NonNull<Slab>>, slab: NonNull<Slab>) {
+        let slab_ref = &mut *slab.as_ptr();
+        slab_ref.prev = None;
+        slab_ref.next = *list;
+        if let Some(next) = *list {
+            (*next.as_ptr()).prev = Some(slab);
+        }
+        *list = Some(slab);
+    }
+
+    unsafe fn remove(list: &mut Option<NonNull<Slab>>, slab: NonNull<Slab>) {
+        let slab_ref = &mut *slab.as_ptr();
+        match slab_ref.prev {
+            None => *list = slab_ref.next,
+            Some(prev) => (*prev.as_ptr()).next = slab_ref.next,
+        }
+        if let Some(next) = slab_ref.next {
+            (*next.as_ptr()).prev = slab_ref.prev;
+        }
+        slab_ref.next = None;
+        slab_ref.prev = None;
+    }
+}
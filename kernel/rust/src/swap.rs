@@ -0,0 +1,281 @@
+use core::alloc::Layout;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::asid::{flush_tlb_page, proc_flush_tlb};
+use crate::c_bindings;
+use crate::interrupts::holding_spinlocks;
+use crate::kalloc::ALLOCATOR;
+use crate::oom::{add_resident, remove_resident};
+use crate::printf::panic;
+use crate::sync::spinlock::Spintex;
+use crate::vm::{leaves, LazyAllocError, PageTableEntry, RSW};
+
+/// Pages that fit in the swap area, which takes up the disk blocks after the file system
+const SWAP_PAGES: usize = c_bindings::NSWAP as usize;
+/// Disk blocks that hold each swapped out page
+const BLOCKS_PER_PAGE: u32 = c_bindings::PGSIZE / c_bindings::BSIZE;
+
+const _: () = assert!(c_bindings::SWAPSIZE == c_bindings::NSWAP * BLOCKS_PER_PAGE);
+
+/// Pages swapped out by each call to [`reclaim`]
+const RECLAIM_BATCH: usize = 8;
+
+/// The number of page table entries referring to each slot of the swap area.
+/// Forked processes share slots until each swaps the page back in
+static SLOTS: Spintex<'static, [u16; SWAP_PAGES]> = Spintex::new([0; SWAP_PAGES], "swap");
+
+/// Set while a CPU is swapping pages out, as only one may move the clock hand at a time
+static RECLAIMING: AtomicBool = AtomicBool::new(false);
+/// The index in the process table of the process the clock hand is on
+static CLOCK_PROC: AtomicUsize = AtomicUsize::new(0);
+/// The address in that process the clock hand is on
+static CLOCK_VA: AtomicU64 = AtomicU64::new(0);
+
+/// Take a free swap slot, or None if the swap area is full
+fn alloc_slot() -> Option<u64> {
+    let mut slots = SLOTS.lock();
+    let slot = slots.iter().position(|refs| *refs == 0)?;
+    slots[slot] = 1;
+    u64::try_from(slot).ok()
+}
+
+/// Whether every slot of the swap area is taken
+pub(crate) fn full() -> bool {
+    !SLOTS.lock().contains(&0)
+}
+
+/// Add a page table entry referring to `slot`.
+/// Returns None if the slot already has as many references as it can count
+pub(crate) fn dup_slot(slot: u64) -> Option<()> {
+    let mut slots = SLOTS.lock();
+    let refs = &mut slots[usize::try_from(slot).unwrap()];
+    *refs = refs.checked_add(1)?;
+    Some(())
+}
+
+/// Drop a page table entry's reference to `slot`, freeing it if it was the last one
+pub(crate) fn free_slot(slot: u64) {
+    let mut slots = SLOTS.lock();
+    let refs = &mut slots[usize::try_from(slot).unwrap()];
+    if *refs == 0 {
+        panic!("free_slot\0");
+    }
+    *refs -= 1;
+}
+
+/// The disk blocks of `slot` of the swap area
+fn slot_blocks(slot: u64) -> impl Iterator<Item = u32> {
+    let first = c_bindings::FSSIZE + u32::try_from(slot).unwrap() * BLOCKS_PER_PAGE;
+    first..first + BLOCKS_PER_PAGE
+}
+
+/// Write the page at `pa` to `slot` of the swap area.
+/// Swap isn't kept across reboots, so it bypasses the log
+fn write_slot(slot: u64, pa: u64) {
+    for (index, blockno) in slot_blocks(slot).enumerate() {
+        let buf = unsafe { &mut *c_bindings::bread(c_bindings::ROOTDEV, blockno) };
+        let src = (pa as *const u8).wrapping_add(index * c_bindings::BSIZE as usize);
+        unsafe {
+            ptr::copy_nonoverlapping(src, buf.data.as_mut_ptr(), c_bindings::BSIZE as usize);
+            c_bindings::bwrite(buf);
+            c_bindings::brelse(buf);
+        }
+    }
+}
+
+/// Read `slot` of the swap area into the page at `pa`
+fn read_slot(slot: u64, pa: u64) {
+    for (index, blockno) in slot_blocks(slot).enumerate() {
+        let buf = unsafe { &mut *c_bindings::bread(c_bindings::ROOTDEV, blockno) };
+        let dst = (pa as *mut u8).wrapping_add(index * c_bindings::BSIZE as usize);
+        unsafe {
+            ptr::copy_nonoverlapping(buf.data.as_ptr(), dst, c_bindings::BSIZE as usize);
+            c_bindings::brelse(buf);
+        }
+    }
+}
+
+/// Read the swapped out page at `va` of `proc` back into memory.
+/// Fails with `NotLazy` if this CPU holds spinlocks, as reading the swap area sleeps
+/// # Safety
+/// `proc` must be the current process, and `va` must be swapped out
+pub(crate) unsafe fn swap_in(proc: &mut c_bindings::proc_, va: u64) -> Result<(), LazyAllocError> {
+    if holding_spinlocks() {
+        return Err(LazyAllocError::NotLazy);
+    }
+    let page_size = c_bindings::PGSIZE as usize;
+    let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+    let page = unsafe { alloc::alloc::alloc(layout) };
+    if page.is_null() {
+        return Err(LazyAllocError::OutOfMemory);
+    }
+
+    let pte = unsafe {
+        c_bindings::walk(proc.pagetable, va, 0)
+            .cast::<PageTableEntry>()
+            .as_mut()
+    }
+    .unwrap();
+    let slot = pte.swap_slot();
+    read_slot(slot, page as u64);
+    // mprotect may have taken user access away while the page was swapped out
+    pte.set_rsw(if pte.user_accessible() {
+        RSW::Default
+    } else {
+        RSW::NoAccess
+    });
+    pte.set_mapping(page);
+    pte.set_valid(true);
+    free_slot(slot);
+    add_resident(proc, 1);
+    flush_tlb_page(proc, va);
+    Ok(())
+}
+
+/// Swap in the swapped out pages of `len` bytes from `va`, and read in the pages of mapped
+/// files there. A copy to or from them holding a spinlock can't, as it sleeps, so the copy
+/// fails and the caller calls this with the spinlock released, then retries.
+/// Returns 0 if any page was brought in, so retrying can get further, or -1 if none was
+/// # Safety
+/// Assumes that `pagetable` is a valid page table
+/// # Panics
+/// Panics if there is no current process
+#[no_mangle]
+pub unsafe extern "C" fn uvmswapin(
+    pagetable: c_bindings::pagetable_t,
+    va: c_bindings::uint64,
+    len: c_bindings::uint64,
+) -> core::ffi::c_int {
+    let proc = unsafe { c_bindings::myproc().as_mut() }.unwrap();
+    if pagetable != proc.pagetable {
+        return -1;
+    }
+    let end = va.saturating_add(len).min(c_bindings::MAXVA);
+    let mut start = va;
+    let mut brought_in = false;
+    // Swapping in changes the page table, so the next swapped out page is looked up afresh
+    while let Some(page) = unsafe { leaves(pagetable, start..end) }
+        .with_swapped()
+        .find_map(|(va, _, pte)| pte.swapped().then_some(va))
+    {
+        if unsafe { swap_in(proc, page) }.is_err() {
+            break;
+        }
+        brought_in = true;
+        start = page + u64::from(c_bindings::PGSIZE);
+    }
+    brought_in |= unsafe { crate::mmap::fault_in(proc, va..end) };
+    if brought_in {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Swap out up to [`RECLAIM_BATCH`] pages that haven't been accessed recently,
+/// found with a clock over the pages of every process that can be swapped from.
+/// Returns true if pages were swapped out, or another CPU is swapping pages out
+/// Must be called without any locks held
+pub(crate) fn reclaim() -> bool {
+    if RECLAIMING.swap(true, Ordering::Acquire) {
+        return true;
+    }
+    let reclaimed = if full() { 0 } else { clock(RECLAIM_BATCH) };
+    RECLAIMING.store(false, Ordering::Release);
+    reclaimed > 0
+}
+
+/// Move the clock hand until `target` pages are swapped out, or it has gone round twice,
+/// which is enough to find the pages whose accessed bits it cleared the first time round.
+/// Returns the number of pages swapped out
+fn clock(target: usize) -> usize {
+    let me = unsafe { c_bindings::myproc() };
+    let procs = unsafe { &mut *ptr::addr_of_mut!(c_bindings::proc_) };
+    let mut reclaimed = 0;
+    for _ in 0..=2 * procs.len() {
+        if reclaimed == target {
+            break;
+        }
+        let index = CLOCK_PROC.load(Ordering::Relaxed);
+        let proc = &mut procs[index];
+        if claim(proc, me) {
+            let start = CLOCK_VA.load(Ordering::Relaxed);
+            let (swapped_out, stopped_at) = unsafe { sweep(proc, start, target - reclaimed) };
+            reclaimed += swapped_out;
+            if !ptr::eq(proc, me) {
+                unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+                proc.pageout = 0;
+                unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+            }
+            if let Some(va) = stopped_at {
+                CLOCK_VA.store(va, Ordering::Relaxed);
+                break;
+            }
+        }
+        CLOCK_PROC.store((index + 1) % procs.len(), Ordering::Relaxed);
+        CLOCK_VA.store(0, Ordering::Relaxed);
+    }
+    reclaimed
+}
+
+/// Stop `proc` from running while its pages are swapped out, if it is the current process,
+/// or was preempted in user mode and so isn't partway through using its memory.
+/// Returns whether its pages can be swapped out
+fn claim(proc: &mut c_bindings::proc_, me: *mut c_bindings::proc_) -> bool {
+    if ptr::eq(proc, me) {
+        return true;
+    }
+    unsafe { c_bindings::acquire(ptr::addr_of_mut!(proc.lock)) };
+    let claimed =
+        proc.state == c_bindings::procstate::RUNNABLE && proc.userpreempt != 0 && proc.pageout == 0;
+    if claimed {
+        proc.pageout = 1;
+    }
+    unsafe { c_bindings::release(ptr::addr_of_mut!(proc.lock)) };
+    claimed
+}
+
+/// Swap out up to `target` pages of `proc` from `start` on that haven't been accessed since
+/// the clock hand last passed them, clearing the accessed bits of those that have.
+/// Only private pages of its heap and stack are swapped out.
+/// Returns the number of pages swapped out, and where it stopped if it reached `target`
+/// or the swap area filled up
+/// # Safety
+/// `proc` must not be running, other than as the current process
+unsafe fn sweep(proc: &mut c_bindings::proc_, start: u64, target: usize) -> (usize, Option<u64>) {
+    let mut swapped_out = 0;
+    let mut stopped_at = None;
+    for (va, level, pte) in unsafe { leaves(proc.pagetable, start..proc.sz) } {
+        if swapped_out == target {
+            stopped_at = Some(va);
+            break;
+        }
+        // Megapages, and pages shared with other processes or the kernel, stay in memory
+        let private = level == 0
+            && pte.user_accessible()
+            && pte.rsw() == RSW::Default
+            && ALLOCATOR.exactly_one_reference(usize::try_from(pte.pa_int()).unwrap());
+        if !private {
+            continue;
+        }
+        if pte.accessed() {
+            pte.clear_accessed();
+            continue;
+        }
+        let Some(slot) = alloc_slot() else {
+            stopped_at = Some(va);
+            break;
+        };
+        let pa = pte.pa_int();
+        write_slot(slot, pa);
+        pte.set_swapped(slot);
+        let page_size = c_bindings::PGSIZE as usize;
+        let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+        unsafe { alloc::alloc::dealloc(pa as *mut u8, layout) };
+        swapped_out += 1;
+    }
+    remove_resident(proc, u64::try_from(swapped_out).unwrap());
+    unsafe { proc_flush_tlb(proc) };
+    (swapped_out, stopped_at)
+}
@@ -1,9 +1,12 @@
 use crate::{
+    asid::proc_flush_tlb,
     c_bindings,
     dev::device_load::{CPU_COUNT, PHYSICAL_ADDRESS_STOP},
+    mmap::has_flag,
     proc::sleep_rust,
     trap::TICKS,
-    vm::{copyout, PageTableEntry},
+    uaccess::{UserPtr, UserSlice},
+    vm::{leaves, level_size, PageTableEntry, PGROUNDDOWN},
 };
 use core::ptr::{self, NonNull};
 
@@ -32,30 +35,42 @@ pub extern "C" fn sys_trace() -> c_bindings::uint64 {
 pub extern "C" fn sys_sysinfo() -> c_bindings::uint64 {
     let proc_count = unsafe { c_bindings::count_proc_not_in_state(c_bindings::procstate::UNUSED) };
     let freemem = crate::kalloc::ALLOCATOR.memfree_count();
+    let (page_cache_hits, page_cache_misses) = crate::kalloc::ALLOCATOR.cpu_cache_stats();
+    let watermarks = crate::oom::watermarks();
     let sysinfo = c_bindings::sysinfo {
         max_mem: unsafe { PHYSICAL_ADDRESS_STOP },
         cpu_count: unsafe { CPU_COUNT },
         freemem,
         nproc: proc_count,
+        page_cache_hits,
+        page_cache_misses,
+        free_pages: watermarks.free as u64,
+        low_watermark: watermarks.low as u64,
+        min_watermark: watermarks.min as u64,
+        faults: crate::fault_stats::totals(),
     };
     let output = argaddr(0);
-    let proc = unsafe { c_bindings::myproc().as_mut() };
-    if let Some(proc) = proc {
-        let copyout_result = unsafe {
-            copyout(
-                proc.pagetable,
-                output,
-                ptr::addr_of!(sysinfo).cast(),
-                core::mem::size_of::<c_bindings::sysinfo>() as u64,
-            )
-        };
-        if copyout_result < 0 {
-            u64::MAX
-        } else {
-            0u64
-        }
-    } else {
-        u64::MAX
+    match unsafe { c_bindings::myproc().as_ref() } {
+        None => u64::MAX,
+        Some(proc) => unsafe { UserPtr::new(proc, output) }
+            .and_then(|output| output.write(&sysinfo))
+            .map_or(u64::MAX, |()| 0),
+    }
+}
+
+/// Copy the page fault counts of the process with a pid to a `struct faultstats`.
+/// The process may have exited, as long as it hasn't been waited for
+#[no_mangle]
+pub extern "C" fn sys_faultstats() -> c_bindings::uint64 {
+    let pid = argint(0);
+    let output = argaddr(1);
+
+    match unsafe { c_bindings::myproc().as_ref() } {
+        None => u64::MAX,
+        Some(proc) => unsafe { UserPtr::new(proc, output) }
+            .zip(crate::fault_stats::of_pid(pid))
+            .and_then(|(output, faults)| output.write(&faults))
+            .map_or(u64::MAX, |()| 0),
     }
 }
 
@@ -68,100 +83,243 @@ pub extern "C" fn sys_shutdown() -> c_bindings::uint64 {
     }
 }
 
+/// Write a bitmap to `out_bitmap` of which of `page_count` pages from `start_va` were accessed
+/// since their bits were last cleared, one bit per page, and clear the bits unless `PGBITS_PEEK`
+/// is set in `flags`. Returns the number of pages in the range that aren't mapped
 #[no_mangle]
-#[allow(clippy::missing_panics_doc)]
 pub extern "C" fn sys_pgaccess() -> c_bindings::uint64 {
+    page_bits_syscall(|pte, peek| {
+        let accessed = pte.accessed();
+        if !peek {
+            pte.clear_accessed();
+        }
+        accessed
+    })
+}
+
+/// Bytes of a page bitmap built up in the kernel before being copied out to the user
+const PAGE_BITMAP_CHUNK: usize = 64;
+
+/// Writes a bitmap of one bit per page to user memory, a chunk at a time
+struct PageBitmapWriter {
+    out_bitmap: UserSlice,
+    chunk: [u8; PAGE_BITMAP_CHUNK],
+    /// Which chunk of the bitmap is being built up
+    chunk_index: u64,
+}
+
+impl PageBitmapWriter {
+    const CHUNK_PAGES: u64 = PAGE_BITMAP_CHUNK as u64 * 8;
+
+    /// Set the bit of the page `page` pages into the bitmap, which must not be before
+    /// the last page set. Returns None if the bitmap couldn't be copied out
+    fn set(&mut self, page: u64) -> Option<()> {
+        while page / Self::CHUNK_PAGES > self.chunk_index {
+            self.copy_out(PAGE_BITMAP_CHUNK)?;
+        }
+        let bit = page % Self::CHUNK_PAGES;
+        self.chunk[usize::try_from(bit / 8).ok()?] |= 1 << (bit % 8);
+        Some(())
+    }
+
+    /// Copy out the rest of a bitmap of `page_count` pages
+    fn finish(mut self, page_count: u64) -> Option<()> {
+        let bitmap_len = page_count.div_ceil(8);
+        let chunk_len = PAGE_BITMAP_CHUNK as u64;
+        while self.chunk_index * chunk_len < bitmap_len {
+            let len = (bitmap_len - self.chunk_index * chunk_len).min(chunk_len);
+            self.copy_out(usize::try_from(len).ok()?)?;
+        }
+        Some(())
+    }
+
+    /// Copy out the first `len` bytes of the current chunk, and start on the next one
+    fn copy_out(&mut self, len: usize) -> Option<()> {
+        self.out_bitmap
+            .subslice(self.chunk_index * PAGE_BITMAP_CHUNK as u64, len as u64)?
+            .write(&self.chunk[..len])?;
+        self.chunk.fill(0);
+        self.chunk_index += 1;
+        Some(())
+    }
+}
+
+/// Read the arguments of `pgaccess` or `pgdirty`, and write the bitmap of pages
+/// for which `test` returns true. `test` is passed whether the bits are only being peeked at
+fn page_bits_syscall(test: impl FnMut(&mut PageTableEntry, bool) -> bool) -> u64 {
     let start_va = argaddr(0);
     let page_count = argint(1);
-    if page_count > 32 {
+    let out_bitmap = argaddr(2);
+    let flags = argint(3);
+    let Ok(page_count) = u64::try_from(page_count) else {
         return u64::MAX;
-    }
-    let out_bitmask = argaddr(2);
-    if out_bitmask == 0 {
+    };
+    if out_bitmap == 0
+        || u32::try_from(flags).map_or(true, |flags| flags & !c_bindings::PGBITS_PEEK != 0)
+    {
         return u64::MAX;
     }
 
     match unsafe { c_bindings::myproc().as_ref() } {
         None => u64::MAX,
         Some(my_process) => {
-            let mut page_bitmask = 0u32;
-            for (page_index, va) in (start_va
-                ..(start_va + u64::from(u32::try_from(page_count).unwrap() * c_bindings::PGSIZE)))
-                .step_by(c_bindings::PGSIZE as usize)
-                .enumerate()
-            {
-                match unsafe { c_bindings::walk(my_process.pagetable, va, 0).as_mut() } {
-                    None => return u64::MAX,
-                    Some(pte) => {
-                        let mut page_table_entry = PageTableEntry(*pte);
-                        if page_table_entry.accessed() {
-                            page_bitmask |= 1 << page_index;
-                            page_table_entry.clear_accessed();
-                            *pte = page_table_entry.into();
-                        }
-                    }
-                }
-            }
-            unsafe {
-                copyout(
+            let Some(out_bitmap) =
+                (unsafe { UserSlice::new(my_process, out_bitmap, page_count.div_ceil(8)) })
+            else {
+                return u64::MAX;
+            };
+            let peek = has_flag(flags, c_bindings::PGBITS_PEEK);
+            let writer = PageBitmapWriter {
+                out_bitmap,
+                chunk: [0; PAGE_BITMAP_CHUNK],
+                chunk_index: 0,
+            };
+            let bits = unsafe {
+                page_bits(
                     my_process.pagetable,
-                    out_bitmask,
-                    ptr::addr_of!(page_bitmask).cast(),
-                    core::mem::size_of_val(&page_bitmask) as u64,
+                    writer,
+                    start_va,
+                    page_count,
+                    test,
+                    peek,
                 )
-            };
-            0
+            }
+            .unwrap_or(u64::MAX);
+            // Cleared bits are only set again once the TLB entries caching them are gone
+            if !peek {
+                unsafe { proc_flush_tlb(c_bindings::myproc()) };
+            }
+            bits
         }
     }
 }
 
+/// Set a bit in `writer` for each of `page_count` pages from `start_va`, if `test` returns
+/// true for the PTE mapping it. Superpages are tested once, setting the bits of all their pages.
+/// Pages that aren't mapped have no bit set, and are counted in the return value.
+/// Returns None if the range is outside of user memory, or the bitmap couldn't be copied out
+/// # Safety
+/// Assumes that `pagetable` is a valid page table
+unsafe fn page_bits(
+    pagetable: c_bindings::pagetable_t,
+    mut writer: PageBitmapWriter,
+    start_va: u64,
+    page_count: u64,
+    mut test: impl FnMut(&mut PageTableEntry, bool) -> bool,
+    peek: bool,
+) -> Option<u64> {
+    let start_va = PGROUNDDOWN!(start_va);
+    let page_size = u64::from(c_bindings::PGSIZE);
+    let end_va = start_va.checked_add(page_count.checked_mul(page_size)?)?;
+    if end_va > c_bindings::MAXVA {
+        return None;
+    }
+
+    let mut mapped_pages = 0;
+    // Copying out the bitmap may map pages, but never frees page-table pages
+    for (va, level, pte) in unsafe { leaves(pagetable, start_va..end_va) } {
+        let first_page = va.max(start_va);
+        let last_page = (va + level_size(level)).min(end_va);
+        mapped_pages += (last_page - first_page) / page_size;
+        if test(pte, peek) {
+            for page in (first_page..last_page).step_by(c_bindings::PGSIZE as usize) {
+                writer.set((page - start_va) / page_size)?;
+            }
+        }
+    }
+    writer.finish(page_count)?;
+    Some(page_count - mapped_pages)
+}
+
+/// Map a file, or anonymous memory if `MAP_ANONYMOUS` is set, into the process.
+/// The address hint is ignored. Returns the start of the mapping
 #[no_mangle]
-#[allow(clippy::missing_panics_doc)]
-pub extern "C" fn sys_pgdirty() -> c_bindings::uint64 {
-    let start_va = argaddr(0);
-    let page_count = argint(1);
-    if page_count > 32 {
-        return u64::MAX;
+pub extern "C" fn sys_mmap() -> c_bindings::uint64 {
+    let len = argaddr(1);
+    let prot = argint(2);
+    let flags = argint(3);
+    let fd = argint(4);
+    let offset = argaddr(5);
+
+    match unsafe { c_bindings::myproc().as_mut() } {
+        None => u64::MAX,
+        Some(my_process) => {
+            let file = usize::try_from(fd)
+                .ok()
+                .and_then(|fd| my_process.ofile.get(fd).copied())
+                .unwrap_or(ptr::null_mut());
+            unsafe { crate::mmap::mmap(my_process, len, prot, flags, file, offset) }
+                .unwrap_or(u64::MAX)
+        }
     }
-    let out_bitmask = argaddr(2);
-    if out_bitmask == 0 {
-        return u64::MAX;
+}
+
+/// Unmap part of a region mapped by mmap, writing shared pages back to the file
+#[no_mangle]
+pub extern "C" fn sys_munmap() -> c_bindings::uint64 {
+    let addr = argaddr(0);
+    let len = argaddr(1);
+
+    match unsafe { c_bindings::myproc().as_mut() } {
+        None => u64::MAX,
+        Some(my_process) => {
+            unsafe { crate::mmap::munmap(my_process, addr, len) }.map_or(u64::MAX, |()| 0)
+        }
     }
+}
 
-    match unsafe { c_bindings::myproc().as_ref() } {
+/// Change the protection of pages below the size of the process, or of a whole region mapped by mmap
+#[no_mangle]
+pub extern "C" fn sys_mprotect() -> c_bindings::uint64 {
+    let addr = argaddr(0);
+    let len = argaddr(1);
+    let prot = argint(2);
+
+    match unsafe { c_bindings::myproc().as_mut() } {
         None => u64::MAX,
         Some(my_process) => {
-            let mut page_bitmask = 0u32;
-            for (page_index, va) in (start_va
-                ..(start_va + u64::from(u32::try_from(page_count).unwrap() * c_bindings::PGSIZE)))
-                .step_by(c_bindings::PGSIZE as usize)
-                .enumerate()
-            {
-                match unsafe { c_bindings::walk(my_process.pagetable, va, 0).as_mut() } {
-                    None => return u64::MAX,
-                    Some(pte) => {
-                        let mut page_table_entry = PageTableEntry(*pte);
-                        if page_table_entry.dirty() {
-                            page_bitmask |= 1 << page_index;
-                            page_table_entry.clear_dirty();
-                            *pte = page_table_entry.into();
-                        }
-                    }
-                }
-            }
-            unsafe {
-                copyout(
-                    my_process.pagetable,
-                    out_bitmask,
-                    ptr::addr_of!(page_bitmask).cast(),
-                    core::mem::size_of_val(&page_bitmask) as u64,
-                );
-            }
-            0
+            unsafe { crate::vm::mprotect(my_process, addr, len, prot) }.map_or(u64::MAX, |()| 0)
         }
     }
 }
 
+/// Map the shared memory segment with a key, creating it with the given size if there is none.
+/// Returns the start of the mapping
+#[no_mangle]
+pub extern "C" fn sys_shmat() -> c_bindings::uint64 {
+    let key = argint(0);
+    let size = argaddr(1);
+
+    match unsafe { c_bindings::myproc().as_mut() } {
+        None => u64::MAX,
+        Some(my_process) => unsafe { crate::shm::shmat(my_process, key, size) }.unwrap_or(u64::MAX),
+    }
+}
+
+/// Unmap the shared memory segment mapped at an address
+#[no_mangle]
+pub extern "C" fn sys_shmdt() -> c_bindings::uint64 {
+    let addr = argaddr(0);
+
+    match unsafe { c_bindings::myproc().as_mut() } {
+        None => u64::MAX,
+        Some(my_process) => unsafe { crate::shm::shmdt(my_process, addr) }.map_or(u64::MAX, |()| 0),
+    }
+}
+
+/// Write a bitmap to `out_bitmap` of which of `page_count` pages from `start_va` were written
+/// since their bits were last cleared, like [`sys_pgaccess`]
+#[no_mangle]
+pub extern "C" fn sys_pgdirty() -> c_bindings::uint64 {
+    page_bits_syscall(|pte, peek| {
+        let dirty = pte.dirty();
+        if !peek {
+            pte.clear_dirty();
+        }
+        dirty
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sys_sigalarm() -> c_bindings::uint64 {
     let my_proc = unsafe { c_bindings::myproc().as_mut() };
@@ -1,12 +1,16 @@
-use core::alloc::Layout;
 use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
 
+use crate::asid::flush_tlb_page;
 use crate::c_bindings;
-use crate::kalloc::ALLOCATOR;
+use crate::fault_stats::{self, Fault};
 use crate::printf::{panic, printf};
 use crate::riscv_asm::{intr_on, r_scause, r_sepc, r_sstatus, r_stval, w_stvec, SSTATUS_SPP};
 use crate::sync::spinlock::Spintex;
-use crate::vm::{PageTableEntry, PGROUNDDOWN, RSW};
+use crate::vm::{
+    break_cow, demand_page, split_megapage, writeable_by_user, LazyAllocError, PageTableEntry,
+    PGROUNDDOWN, RSW,
+};
 
 extern "C" {
     pub fn kernelvec();
@@ -64,6 +68,7 @@ pub extern "C" fn usertrap() {
                 }
             }
             2 => {
+                crate::oom::check_pressure();
                 if proc.alarm_interval > 0 {
                     proc.ticks_since_last_alarm += 1;
                 }
@@ -84,49 +89,25 @@ pub extern "C" fn usertrap() {
             3 => {
                 let va_write_fault_page = PGROUNDDOWN!(r_stval!());
                 if va_write_fault_page >= c_bindings::MAXVA {
+                    fault_stats::count(proc, Fault::Fatal);
                     unsafe {
                         c_bindings::setkilled(proc);
                         c_bindings::exit(-1);
                     }
                 }
 
-                match unsafe {
-                    c_bindings::walk(proc.pagetable, va_write_fault_page, 0)
-                        .cast::<PageTableEntry>()
-                        .as_mut()
-                } {
-                    None => unsafe { c_bindings::setkilled(proc) },
-                    Some(va_pte) => {
-                        if va_pte.rsw() == RSW::COWPage && !va_pte.writeable() {
-                            va_pte.set_rsw(RSW::Default);
-                            if ALLOCATOR
-                                .exactly_one_reference(usize::try_from(va_pte.pa_int()).unwrap())
-                            {
-                                va_pte.set_writeable(true);
-                            } else {
-                                let page_size = c_bindings::PGSIZE as usize;
-                                let layout = unsafe {
-                                    Layout::from_size_align_unchecked(page_size, page_size)
-                                };
-                                let new_page = unsafe { alloc::alloc::alloc(layout) };
-                                if new_page.is_null() {
-                                    unsafe { c_bindings::setkilled(proc) };
-                                } else {
-                                    let pa = va_pte.pa_mut().as_mut_ptr();
-                                    unsafe {
-                                        core::ptr::copy_nonoverlapping(pa, new_page, page_size);
-                                    }
-                                    va_pte.set_writeable(true);
-                                    va_pte.set_mapping(new_page);
-                                    unsafe { alloc::alloc::dealloc(pa, layout) };
-                                }
-                            }
-                        } else {
-                            unsafe { c_bindings::setkilled(proc) }
-                        }
+                // COW is handled one page at a time, so megapages are split first
+                if unsafe { split_megapage(proc, va_write_fault_page) }.is_none() {
+                    // Leave the megapage whole, so the write faults again once the
+                    // OOM killer's victim has given memory back
+                    if !crate::oom::out_of_memory() {
+                        unsafe { c_bindings::setkilled(proc) };
                     }
+                } else {
+                    handle_store_fault(proc, va_write_fault_page);
                 }
             }
+            4 => handle_lazy_fault(proc, r_stval!(), false),
             _ => {}
         }
     }
@@ -138,18 +119,88 @@ pub extern "C" fn usertrap() {
     }
 
     if which_dev == 2 {
+        // Nothing in the kernel is using its memory, so it can be swapped out meanwhile
+        proc.userpreempt = 1;
         unsafe { c_bindings::yield_() };
+        proc.userpreempt = 0;
     }
 
     unsafe { c_bindings::usertrapret(0) };
 }
 
+/// Resolve a store fault on the page at `va`, by copying it if it is a COW page,
+/// or mapping it if it was lazily allocated
+fn handle_store_fault(proc: &mut c_bindings::proc_, va: u64) {
+    match unsafe {
+        c_bindings::walk(proc.pagetable, va, 0)
+            .cast::<PageTableEntry>()
+            .as_mut()
+    } {
+        None => handle_lazy_fault(proc, va, true),
+        Some(va_pte) if !va_pte.valid() => {
+            handle_lazy_fault(proc, va, true);
+        }
+        Some(va_pte) => {
+            if va_pte.rsw() == RSW::COWPage && !va_pte.writeable() && writeable_by_user(proc, va) {
+                match unsafe { break_cow(va_pte) } {
+                    Ok(fault) => {
+                        fault_stats::count(proc, fault);
+                        // Drop the read-only translation of the page
+                        flush_tlb_page(proc, va);
+                    }
+                    Err(_) => {
+                        // Leave the page COW, so the write faults again once the
+                        // OOM killer's victim has given memory back
+                        if !crate::oom::out_of_memory() {
+                            unsafe { c_bindings::setkilled(proc) };
+                        }
+                    }
+                }
+            } else {
+                kill_faulting(proc, va);
+            }
+        }
+    }
+}
+
+/// Map a page at `va` if the stack can grow to it or `sbrk` or mmap reserved it, killing the process
+/// if the fault was outside of its memory
+fn handle_lazy_fault(proc: &mut c_bindings::proc_, va: u64, write: bool) {
+    match unsafe { demand_page(proc, va, write) } {
+        Ok(()) => fault_stats::count(proc, Fault::Minor),
+        Err(LazyAllocError::OutOfMemory) => {
+            // Leave the page unmapped, so the access faults again once pages
+            // are swapped out, or the OOM killer's victim has given memory back
+            if !crate::oom::out_of_memory() {
+                unsafe { c_bindings::setkilled(proc) };
+            }
+        }
+        Err(LazyAllocError::NotLazy) => kill_faulting(proc, va),
+    }
+}
+
+/// Kill `proc` for a bad access to `va`, reporting it if it hit the guard page below the stack
+fn kill_faulting(proc: &mut c_bindings::proc_, va: u64) {
+    let guard_page = proc
+        .stacklimit
+        .checked_sub(u64::from(c_bindings::PGSIZE))
+        .map_or(0..0, |guard_start| guard_start..proc.stacklimit);
+    if guard_page.contains(&va) {
+        printf!(b"stack overflow pid=%d\n\0", proc.pid);
+    }
+    fault_stats::count(proc, Fault::Fatal);
+    unsafe { c_bindings::setkilled(proc) };
+}
+
 pub(crate) static TICKS: Spintex<'static, u32> = Spintex::new(0, "time");
+/// A copy of `TICKS` for code that can't take its lock, like the allocator
+pub(crate) static TICKS_UNLOCKED: AtomicU32 = AtomicU32::new(0);
 
 #[no_mangle]
 pub extern "C" fn clockintr() {
     let mut ticks = TICKS.lock();
     *ticks += 1;
+    TICKS_UNLOCKED.store(*ticks, Ordering::Relaxed);
     unsafe {
         c_bindings::wakeup(NonNull::from(&TICKS).as_ptr().cast());
     }
@@ -0,0 +1,403 @@
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::asid::flush_tlb_page;
+use crate::c_bindings;
+use crate::fault_stats::{self, Fault};
+use crate::interrupts::{pop_off, push_off};
+use crate::riscv_asm::{sfence_vma_asid, sfence_vma_page};
+use crate::vm::{
+    break_cow, demand_page, split_megapage, walk_leaf, writeable_by_user, MEGAPAGE_SIZE,
+    PAGING_LEVELS, PGROUNDDOWN, RSW,
+};
+
+/// A range of the current process's user memory, checked to lie within its heap and stack,
+/// or within a single region mapped with mmap.
+/// Each page is checked again when it is copied to or from, and faults on it are recovered
+/// from, so a bad address is an error rather than a kernel panic
+#[derive(Clone, Copy)]
+pub(crate) struct UserSlice {
+    pagetable: c_bindings::pagetable_t,
+    va: u64,
+    len: u64,
+}
+
+impl UserSlice {
+    /// The `len` bytes of `proc`'s memory at `va`, or None if they aren't all user memory
+    /// # Safety
+    /// `proc` must be the current process, and the slice only used while it is
+    pub(crate) unsafe fn new(proc: &c_bindings::proc_, va: u64, len: u64) -> Option<Self> {
+        let end = va.checked_add(len)?;
+        if len > 0 && region_end(proc, va)? < end {
+            return None;
+        }
+        Some(UserSlice {
+            pagetable: proc.pagetable,
+            va,
+            len,
+        })
+    }
+
+    /// The `len` bytes of the slice starting `offset` bytes in, or None if that runs past its end
+    pub(crate) fn subslice(&self, offset: u64, len: u64) -> Option<Self> {
+        if offset.checked_add(len)? > self.len {
+            return None;
+        }
+        Some(UserSlice {
+            pagetable: self.pagetable,
+            va: self.va + offset,
+            len,
+        })
+    }
+
+    /// Copy `src` to the start of the slice.
+    /// Returns None if `src` is longer than the slice, or the slice isn't writeable
+    pub(crate) fn write(&self, src: &[u8]) -> Option<()> {
+        let len = u64::try_from(src.len()).ok()?;
+        if len > self.len {
+            return None;
+        }
+        unsafe { copyout(self.pagetable, self.va, src.as_ptr(), len) == 0 }.then_some(())
+    }
+}
+
+/// A `T` in the current process's user memory, checked like a [`UserSlice`]
+pub(crate) struct UserPtr<T> {
+    slice: UserSlice,
+    _value: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    /// The `T` at `va` in `proc`'s memory, or None if it isn't all user memory
+    /// # Safety
+    /// `proc` must be the current process, and the pointer only used while it is
+    pub(crate) unsafe fn new(proc: &c_bindings::proc_, va: u64) -> Option<Self> {
+        let len = u64::try_from(core::mem::size_of::<T>()).ok()?;
+        Some(UserPtr {
+            slice: unsafe { UserSlice::new(proc, va, len) }?,
+            _value: PhantomData,
+        })
+    }
+
+    /// Copy `value` out to the user. Returns None if the memory isn't writeable
+    pub(crate) fn write(&self, value: &T) -> Option<()> {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                core::ptr::addr_of!(*value).cast::<u8>(),
+                core::mem::size_of::<T>(),
+            )
+        };
+        self.slice.write(bytes)
+    }
+}
+
+/// The end of the region of `proc`'s user memory containing `va`: its heap and stack below
+/// `proc.sz`, or a region mapped with mmap. Returns None if `va` isn't in user memory
+fn region_end(proc: &c_bindings::proc_, va: u64) -> Option<u64> {
+    if va < proc.sz {
+        return Some(proc.sz);
+    }
+    proc.vmas
+        .iter()
+        .find(|vma| vma.len > 0 && vma.addr <= va && va < vma.addr + vma.len)
+        .map(|vma| vma.addr + vma.len)
+}
+
+/// Get the user page at `va` in `pagetable` ready to be copied to, if `write` is set, or from.
+/// For the current process, `va` must be in its user memory, and pages it reserved without
+/// mapping are mapped first. COW pages the user may write to are copied.
+/// Pages that are still unmapped, or read-only, are left for the copy to fault on.
+/// Returns None if `va` can't be accessed, including pages mapped for the kernel only,
+/// which the kernel could touch without faulting
+/// # Safety
+/// Assumes that `pagetable` is a valid page table
+unsafe fn prepare(pagetable: c_bindings::pagetable_t, va: u64, write: bool) -> Option<()> {
+    let va0 = PGROUNDDOWN!(va);
+    if va0 >= c_bindings::MAXVA {
+        return None;
+    }
+    // exec copies into a page table that is not the process's yet
+    let proc = unsafe { c_bindings::myproc().as_mut() }?;
+    let current = pagetable == proc.pagetable;
+    if current {
+        region_end(proc, va0)?;
+        // Pages that still can't be mapped fault when they are copied
+        if unsafe { demand_page(proc, va0, write) }.is_ok() {
+            fault_stats::count(proc, Fault::Minor);
+        }
+    }
+
+    loop {
+        let Some((pte, page_size)) = (unsafe { walk_leaf(pagetable, va0) }) else {
+            return Some(());
+        };
+        // Guard pages, and pages mapped with PROT_NONE
+        if !pte.user_accessible() {
+            return None;
+        }
+        // Read-only pages, like those mapped without PROT_WRITE, even if they are COW.
+        // Page tables that aren't in use yet have no COW pages
+        if !write
+            || pte.writeable()
+            || pte.rsw() != RSW::COWPage
+            || !current
+            || !writeable_by_user(proc, va0)
+        {
+            return Some(());
+        }
+        // COW megapages are split, then the page is looked up again
+        if page_size == MEGAPAGE_SIZE {
+            unsafe { split_megapage(proc, va0) }?;
+            continue;
+        }
+        let fault = unsafe { break_cow(pte) }.ok()?;
+        fault_stats::count(proc, fault);
+        flush_tlb_page(proc, va0);
+        return Some(());
+    }
+}
+
+/// The number of user page tables freed, so harts know to flush the ones they aliased
+static PAGE_TABLES_FREED: AtomicU64 = AtomicU64::new(0);
+
+/// Note that a user page table is about to be freed, so that harts that aliased it
+/// flush its page-table pages from their TLBs before they alias user memory again
+#[no_mangle]
+pub extern "C" fn user_unalias() {
+    PAGE_TABLES_FREED.fetch_add(1, Ordering::Release);
+}
+
+/// Alias the user memory of `pagetable` above `MAXVA` in this hart's root page table,
+/// and return the address of the user byte at `va` there. The kernel can reach it
+/// with `SSTATUS_SUM` set, faulting wherever the user would.
+/// The whole alias is only set up when this hart last aliased another page table,
+/// so usually once per switch to a process; after that only the root PTE of `va` is checked.
+/// The alias must only be used while interrupts stay off, as other harts alias other memory
+/// # Safety
+/// Assumes that `pagetable` is a valid page table, and `va` is below `MAXVA`
+unsafe fn user_alias(pagetable: c_bindings::pagetable_t, va: u64) -> *mut u8 {
+    let cpu = unsafe { &mut *c_bindings::mycpu() };
+    let root = cpu.pagetable;
+    // User memory is all below MAXVA, in the low half of the 512 PTEs of the root page table
+    let half = 256;
+    let freed = PAGE_TABLES_FREED.load(Ordering::Acquire);
+    // The kernel's TLB entries may hold the page tables it aliased before,
+    // even unchanged ones, if they were freed and reused since
+    if cpu.aliased != pagetable || cpu.tablesfreed != freed {
+        unsafe { core::ptr::copy_nonoverlapping(pagetable, root.add(half), half) };
+        cpu.aliased = pagetable;
+        cpu.tablesfreed = freed;
+        sfence_vma_asid!(0);
+    } else {
+        // The page table may have grown a new root PTE since
+        let index = usize::try_from((va >> (12 + 9 * (PAGING_LEVELS - 1))) & 0x1FF).unwrap();
+        let alias = unsafe { &mut *root.add(half + index) };
+        let pte = unsafe { *pagetable.add(index) };
+        if *alias != pte {
+            *alias = pte;
+            sfence_vma_asid!(0);
+        }
+    }
+    let alias = va | !(c_bindings::MAXVA - 1);
+    // Targeted flushes of user pages only reach the user's ASID
+    sfence_vma_page!(PGROUNDDOWN!(alias), 0);
+    alias as *mut u8
+}
+
+/// Copy `len` bytes of user memory at `va` in `pagetable` a page at a time, calling `copy` with
+/// the kernel's alias of each piece, its length, and how far into the copy it starts.
+/// `copy` is called with interrupts off, and returns whether to keep going,
+/// or None if touching the memory faulted.
+/// Returns None if the memory can't be read, or written if `write` is set
+/// # Safety
+/// Assumes that `pagetable` is a valid page table
+unsafe fn copy_user(
+    pagetable: c_bindings::pagetable_t,
+    mut va: u64,
+    len: u64,
+    write: bool,
+    mut copy: impl FnMut(*mut u8, usize, usize) -> Option<bool>,
+) -> Option<()> {
+    let mut done = 0;
+    while done < len {
+        let n =
+            (u64::from(c_bindings::PGSIZE) - va % u64::from(c_bindings::PGSIZE)).min(len - done);
+        let (piece, offset) = (usize::try_from(n).ok()?, usize::try_from(done).ok()?);
+        // Preparing the page may sleep, so it is done before interrupts are turned off
+        unsafe { prepare(pagetable, va, write) }?;
+        push_off();
+        let keep_going = copy(unsafe { user_alias(pagetable, va) }, piece, offset);
+        pop_off();
+        if !keep_going? {
+            break;
+        }
+        done += n;
+        va += n;
+    }
+    Some(())
+}
+
+/// An entry of the exception table, which the linker gathers between `ex_table_start` and
+/// `ex_table_end`. A fault in the kernel at `insn` resumes at `fixup` instead of panicking
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: u64,
+    fixup: u64,
+}
+
+extern "C" {
+    static ex_table_start: ExceptionTableEntry;
+    static ex_table_end: ExceptionTableEntry;
+
+    /// Copy `len` bytes from `src` to `dst`, with `SSTATUS_SUM` set so either can be user memory.
+    /// Returns 0, or -1 if a load or store faulted
+    fn copy_user_bytes(dst: *mut u8, src: *const u8, len: usize) -> core::ffi::c_int;
+    /// Copy bytes from `src` to `dst` up to and including a nul, but no more than `max`,
+    /// with `SSTATUS_SUM` set so `src` can be user memory.
+    /// Returns the length of the string if its nul was copied, `max` if there was none
+    /// in the first `max` bytes, or -1 if a load faulted
+    fn copy_user_str(dst: *mut u8, src: *const u8, max: usize) -> core::ffi::c_int;
+}
+
+// Loads and stores that touch user memory are listed in the exception table,
+// so a fault on them makes the copy return -1.
+// SSTATUS_SUM, bit 18, lets the kernel touch pages mapped for the user
+core::arch::global_asm!(
+    ".pushsection .text.copy_user_bytes, \"ax\", @progbits",
+    ".globl copy_user_bytes",
+    "copy_user_bytes:",
+    "    lui t1, 0x40",
+    "    csrs sstatus, t1",
+    "    beqz a2, 3f",
+    "1:  lb t0, 0(a1)",
+    "2:  sb t0, 0(a0)",
+    "    addi a0, a0, 1",
+    "    addi a1, a1, 1",
+    "    addi a2, a2, -1",
+    "    bnez a2, 1b",
+    "3:  li a0, 0",
+    "    csrc sstatus, t1",
+    "    ret",
+    "4:  li a0, -1",
+    "    csrc sstatus, t1",
+    "    ret",
+    ".popsection",
+    ".pushsection .ex_table, \"a\", @progbits",
+    ".balign 8",
+    ".dword 1b, 4b",
+    ".dword 2b, 4b",
+    ".popsection",
+);
+
+core::arch::global_asm!(
+    ".pushsection .text.copy_user_str, \"ax\", @progbits",
+    ".globl copy_user_str",
+    "copy_user_str:",
+    "    lui t2, 0x40",
+    "    csrs sstatus, t2",
+    "    li t1, 0",
+    "1:  beq t1, a2, 3f",
+    "2:  lb t0, 0(a1)",
+    "    sb t0, 0(a0)",
+    "    beqz t0, 3f",
+    "    addi a0, a0, 1",
+    "    addi a1, a1, 1",
+    "    addi t1, t1, 1",
+    "    j 1b",
+    "3:  mv a0, t1",
+    "    csrc sstatus, t2",
+    "    ret",
+    "4:  li a0, -1",
+    "    csrc sstatus, t2",
+    "    ret",
+    ".popsection",
+    ".pushsection .ex_table, \"a\", @progbits",
+    ".balign 8",
+    ".dword 2b, 4b",
+    ".popsection",
+);
+
+/// Where to resume after a fault in the kernel at `sepc`, or 0 if the faulting instruction
+/// isn't in the exception table, so the fault can't be recovered from
+#[no_mangle]
+// Taking the address of an extern static stopped needing unsafe after our MSRV
+#[allow(unused_unsafe)]
+pub extern "C" fn exception_fixup(sepc: c_bindings::uint64) -> c_bindings::uint64 {
+    let start = unsafe { core::ptr::addr_of!(ex_table_start) };
+    let end = unsafe { core::ptr::addr_of!(ex_table_end) };
+    let len = (end as usize - start as usize) / core::mem::size_of::<ExceptionTableEntry>();
+    let table = unsafe { core::slice::from_raw_parts(start, len) };
+    table
+        .iter()
+        .find(|entry| entry.insn == sepc)
+        .map_or(0, |entry| entry.fixup)
+}
+
+/// Copy from kernel to user
+/// Copy len bytes from src to virtual address dstva in a given page table.
+/// Return 0 on sucess, -1 on error.
+/// # Safety
+/// `src` must be valid for reads of `len` bytes, and `pagetable` a valid page table
+#[no_mangle]
+pub unsafe extern "C" fn copyout(
+    pagetable: c_bindings::pagetable_t,
+    dstva: c_bindings::uint64,
+    src: *const u8,
+    len: c_bindings::uint64,
+) -> core::ffi::c_int {
+    let copied = unsafe {
+        copy_user(pagetable, dstva, len, true, |pa, n, done| {
+            (copy_user_bytes(pa, src.add(done), n) == 0).then_some(true)
+        })
+    };
+    copied.map_or(-1, |()| 0)
+}
+
+/// Copy from user to kernel
+/// Copy len bytes to dst from virtual address srcva in a given page table.
+/// Return 0 on success, -1 on error.
+/// # Safety
+/// `dst` must be valid for writes of `len` bytes, and `pagetable` a valid page table
+#[no_mangle]
+pub unsafe extern "C" fn copyin(
+    pagetable: c_bindings::pagetable_t,
+    dst: *mut core::ffi::c_char,
+    srcva: c_bindings::uint64,
+    len: c_bindings::uint64,
+) -> core::ffi::c_int {
+    let copied = unsafe {
+        copy_user(pagetable, srcva, len, false, |pa, n, done| {
+            (copy_user_bytes(dst.add(done).cast(), pa, n) == 0).then_some(true)
+        })
+    };
+    copied.map_or(-1, |()| 0)
+}
+
+/// Copy a null-terminated string from user to kernel.
+/// Copy bytes to dst from virtual address srcva in a given page table,
+/// until a '\0', or max.
+/// Return 0 on success, -1 on error.
+/// # Safety
+/// `dst` must be valid for writes of `max` bytes, and `pagetable` a valid page table
+#[no_mangle]
+pub unsafe extern "C" fn copyinstr(
+    pagetable: c_bindings::pagetable_t,
+    dst: *mut core::ffi::c_char,
+    srcva: c_bindings::uint64,
+    max: c_bindings::uint64,
+) -> core::ffi::c_int {
+    let mut got_null = false;
+    // Pages past the end of the string are never touched, so needn't be user memory
+    let copied = unsafe {
+        copy_user(pagetable, srcva, max, false, |pa, n, done| {
+            let len = usize::try_from(copy_user_str(dst.add(done).cast(), pa, n)).ok()?;
+            got_null = len < n;
+            Some(!got_null)
+        })
+    };
+    if copied.is_some() && got_null {
+        0
+    } else {
+        -1
+    }
+}
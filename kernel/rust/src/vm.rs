@@ -1,14 +1,19 @@
 use core::alloc::Layout;
+use core::marker::PhantomData;
 
 use bitfield::{bitfield, BitMut, BitRange};
 use num_enum::{FromPrimitive, IntoPrimitive};
 
+use crate::asid::{flush_tlb_page, proc_flush_tlb};
 use crate::c_bindings;
+use crate::fault_stats::Fault;
 use crate::kalloc::ALLOCATOR;
+use crate::mmap::{has_flag, protection_allows, pte_permissions};
+use crate::oom::add_resident;
 use crate::printf::{panic, printf};
 
 bitfield! {
-    /// A wrapper around a Sv39 Page Table Entry
+    /// A wrapper around a Sv39 or Sv48 Page Table Entry, which share a layout
     #[derive(PartialEq, Eq, Copy, Clone)]
     #[repr(transparent)]
     pub struct PageTableEntry(u64);
@@ -89,6 +94,44 @@ impl PageTableEntry {
     pub fn get_flags(&self) -> u64 {
         self.bit_range(7, 0)
     }
+
+    /// Does this PTE map a page, rather than point to the next level of the page table?
+    #[must_use]
+    pub fn is_leaf(&self) -> bool {
+        self.valid() && (self.readable() || self.writeable() || self.executable())
+    }
+
+    /// Was the page swapped out? Its contents are then in swap slot [`swap_slot`]
+    #[must_use]
+    pub fn swapped(&self) -> bool {
+        !self.valid() && self.rsw() == RSW::SwappedOrShared
+    }
+
+    /// Is the page part of a shared memory segment? Such pages are never copied on write or swapped out
+    #[must_use]
+    pub fn shared_segment(&self) -> bool {
+        self.valid() && self.rsw() == RSW::SwappedOrShared
+    }
+
+    /// Mark the page as part of a shared memory segment
+    pub fn set_shared_segment(&mut self) {
+        self.set_rsw(RSW::SwappedOrShared);
+    }
+
+    /// The swap slot holding the contents of a swapped out page
+    #[must_use]
+    pub fn swap_slot(&self) -> u64 {
+        self.pa()
+    }
+
+    /// Mark the page as swapped out to `slot`, keeping its permissions for when it is swapped back in
+    pub fn set_swapped(&mut self, slot: u64) {
+        self.set_valid(false);
+        self.set_rsw(RSW::SwappedOrShared);
+        self.set_pa(slot);
+        self.clear_accessed();
+        self.clear_dirty();
+    }
 }
 
 impl From<PageTableEntry> for u64 {
@@ -112,33 +155,375 @@ pub enum RSW {
     Default,
     /// Set if the page in question is a COWable page (Writeable, but COW'd)
     COWPage,
+    /// Set if user access to a page with no other marker was revoked with mprotect(PROT_NONE)
+    NoAccess,
+    /// Set on invalid PTEs of pages that were swapped out,
+    /// and on valid PTEs of pages of a shared memory segment
+    SwappedOrShared,
 }
 
-/// Prints out the mapped pages of the page table
+/// Prints out the mapped pages of the page table, each after the page-table pages leading to it
 /// # Safety
 /// Assumes that the page table passed in is a valid page table
 #[no_mangle]
 pub unsafe extern "C" fn vmprint(pagetable: c_bindings::pagetable_t) {
     printf!(b"page table  %p\n\0", pagetable);
 
-    vmprint_subtable(pagetable, 3u16);
+    unsafe { vmprint_table(pagetable, 0, PAGING_LEVELS - 1) };
 }
 
-unsafe fn vmprint_subtable(pagetable: c_bindings::pagetable_t, level: u16) {
-    if level == 0 {
-        return;
+/// Prints the valid PTEs of `pagetable`, `level` levels above the last one, which maps
+/// the memory from `base_va`, and the page-table pages below them.
+/// Page-table pages that map nothing are printed too
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+unsafe fn vmprint_table(pagetable: c_bindings::pagetable_t, base_va: u64, level: u32) {
+    for pte_index in 0..512 {
+        let pte = unsafe { *pagetable.add(pte_index).cast::<PageTableEntry>() };
+        if !pte.valid() {
+            continue;
+        }
+        let va = base_va + ((pte_index as u64) << (12 + 9 * level));
+        vmprint_pte(va, level, pte);
+        if level > 0 && !pte.is_leaf() {
+            unsafe { vmprint_table(pte.pa_int() as c_bindings::pagetable_t, va, level - 1) };
+        }
+    }
+}
+
+fn vmprint_pte(va: u64, level: u32, pte: PageTableEntry) {
+    let pte_index = (va >> (12 + 9 * level)) & 0x1FF;
+    for _ in 0..(PAGING_LEVELS - level) {
+        printf!(b"..\0");
+    }
+    if level > 0 && pte.is_leaf() {
+        // A superpage, which has no next level to print
+        let page_size_mib = level_size(level) >> 20;
+        printf!(
+            b"%d: pte %p pa %p (%dM)\n\0",
+            pte_index,
+            u64::from(pte),
+            pte.pa_int(),
+            page_size_mib
+        );
+    } else {
+        printf!(
+            b"%d: pte %p pa %p\n\0",
+            pte_index,
+            u64::from(pte),
+            pte.pa_int()
+        );
+    }
+}
+
+/// The number of levels of page tables, 3 for Sv39 or 4 for Sv48
+pub const PAGING_LEVELS: u32 = c_bindings::PAGING_LEVELS;
+
+/// The size of the memory mapped by a leaf PTE in a level 1 page table, a megapage
+pub const MEGAPAGE_SIZE: u64 = 1 << 21;
+
+/// Return the PTE for `va` in the page table `level` levels above the last one,
+/// creating any required page-table pages if `alloc` is set.
+/// Stops early at leaf PTEs of superpages, returning them
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+pub(crate) unsafe fn walk_level(
+    pagetable: c_bindings::pagetable_t,
+    va: u64,
+    level: u32,
+    alloc: bool,
+) -> Option<&'static mut PageTableEntry> {
+    if va >= c_bindings::MAXVA {
+        panic!("walk_level\0");
+    }
+
+    let mut pagetable = pagetable.cast::<PageTableEntry>();
+    for current_level in (level..PAGING_LEVELS).rev() {
+        let index = (va >> (12 + 9 * current_level)) & 0x1FF;
+        let pte = unsafe { &mut *pagetable.add(usize::try_from(index).unwrap()) };
+        if current_level == level || pte.is_leaf() {
+            return Some(pte);
+        }
+        if pte.valid() {
+            pagetable = pte.pa_int() as *mut PageTableEntry;
+        } else {
+            if !alloc {
+                return None;
+            }
+            let page_size = c_bindings::PGSIZE as usize;
+            let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+            let table = unsafe { alloc::alloc::alloc_zeroed(layout) };
+            if table.is_null() {
+                return None;
+            }
+            *pte = PageTableEntry(u64::from(c_bindings::PTE_V));
+            pte.set_mapping(table);
+            pagetable = table.cast();
+        }
+    }
+    None
+}
+
+/// Find the leaf PTE mapping the page containing `va`, along with the size of that page,
+/// which is [`MEGAPAGE_SIZE`] for megapages. Returns None if `va` is not mapped
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+pub(crate) unsafe fn walk_leaf(
+    pagetable: c_bindings::pagetable_t,
+    va: u64,
+) -> Option<(&'static mut PageTableEntry, u64)> {
+    let pte = unsafe { walk_level(pagetable, va, 1, false) }?;
+    if pte.is_leaf() {
+        return Some((pte, MEGAPAGE_SIZE));
+    }
+    let pte = unsafe { walk_level(pagetable, va, 0, false) }?;
+    pte.valid().then_some((pte, u64::from(c_bindings::PGSIZE)))
+}
+
+/// The size of the memory mapped by a leaf PTE in a page table `level` levels above the last one
+#[must_use]
+pub const fn level_size(level: u32) -> u64 {
+    1 << (12 + 9 * level)
+}
+
+/// An iterator over the valid leaf PTEs of a page table that map memory in a range,
+/// yielding the virtual address each maps, the level of the page table it is in, and the PTE.
+/// A superpage that starts before the range is yielded with its own address.
+/// Each leaf is found with a walk from the root, and invalid PTEs skip the whole
+/// range they would map, so unmapped parts of the address space cost one PTE each
+pub struct Leaves<'a> {
+    pagetable: *mut PageTableEntry,
+    va: u64,
+    end: u64,
+    swapped: bool,
+    _pagetable: PhantomData<&'a mut PageTableEntry>,
+}
+
+/// Iterate over the valid leaf PTEs of `pagetable` mapping memory in `range`
+/// # Safety
+/// Assumes that the page table passed in is a valid page table, which isn't freed while the
+/// iterator is in use. Each leaf is walked to afresh, so mappings may change in between
+pub(crate) unsafe fn leaves<'a>(
+    pagetable: c_bindings::pagetable_t,
+    range: core::ops::Range<u64>,
+) -> Leaves<'a> {
+    Leaves {
+        pagetable: pagetable.cast(),
+        va: PGROUNDDOWN!(range.start),
+        end: range.end.min(c_bindings::MAXVA),
+        swapped: false,
+        _pagetable: PhantomData,
+    }
+}
+
+impl Leaves<'_> {
+    /// Yield the PTEs of swapped out pages too, which aren't valid
+    #[must_use]
+    pub fn with_swapped(self) -> Self {
+        Self {
+            swapped: true,
+            ..self
+        }
     }
+}
 
-    for pte_index in 0..512isize {
-        let pte: *const c_bindings::pte_t = pagetable.offset(pte_index);
-        if (*pte & u64::from(c_bindings::PTE_V)) != 0 {
-            let pte_va = (*pte >> 10) << 12;
-            for _ in 0..(4 - level) {
-                printf!(b"..\0");
+impl<'a> Iterator for Leaves<'a> {
+    type Item = (u64, u32, &'a mut PageTableEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        'walk: while self.va < self.end {
+            let mut pagetable = self.pagetable;
+            for level in (0..PAGING_LEVELS).rev() {
+                let index = (self.va >> (12 + 9 * level)) & 0x1FF;
+                let pte = unsafe { &mut *pagetable.add(usize::try_from(index).unwrap()) };
+                let start = self.va & !(level_size(level) - 1);
+                if !pte.valid() {
+                    self.va = start + level_size(level);
+                    if self.swapped && level == 0 && pte.swapped() {
+                        return Some((start, level, pte));
+                    }
+                    continue 'walk;
+                }
+                if level == 0 || pte.is_leaf() {
+                    self.va = start + level_size(level);
+                    return Some((start, level, pte));
+                }
+                pagetable = pte.pa_int() as *mut PageTableEntry;
             }
-            printf!(b"%d: pte %p pa %p\n\0", pte_index, *pte, pte_va);
-            vmprint_subtable(pte_va as c_bindings::pagetable_t, level - 1);
         }
+        None
+    }
+}
+
+/// Split the megapage of `proc` mapping `va`, if there is one, into a page table of single pages
+/// with the same permissions. Each page keeps the reference the megapage held to it.
+/// Returns None if there was no memory for the new page table
+/// # Safety
+/// Assumes that `proc` has a valid page table
+#[allow(clippy::cast_ptr_alignment)]
+pub(crate) unsafe fn split_megapage(proc: &mut c_bindings::proc_, va: u64) -> Option<()> {
+    let Some(pte) = unsafe { walk_level(proc.pagetable, va, 1, false) }.filter(|pte| pte.is_leaf())
+    else {
+        return Some(());
+    };
+    let page_size = c_bindings::PGSIZE as usize;
+    let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+    let table = unsafe { alloc::alloc::alloc_zeroed(layout) }.cast::<PageTableEntry>();
+    if table.is_null() {
+        return None;
+    }
+    for page in 0..512 {
+        let mut page_pte = *pte;
+        page_pte.set_mapping((pte.pa_int() + page * u64::from(c_bindings::PGSIZE)) as *mut u8);
+        unsafe { table.add(usize::try_from(page).unwrap()).write(page_pte) };
+    }
+    *pte = PageTableEntry(u64::from(c_bindings::PTE_V));
+    pte.set_mapping(table.cast());
+    // Flushing any page of the megapage drops its TLB entry
+    flush_tlb_page(proc, va);
+    Some(())
+}
+
+/// Split the megapage of `proc` that `va` falls in the middle of, if there is one,
+/// so the pages from `va` on can be unmapped on their own.
+/// Returns 0 on success, -1 if out of memory
+/// # Safety
+/// `proc` must be a valid process with a valid page table
+#[no_mangle]
+pub unsafe extern "C" fn uvmsplit(
+    proc: *mut c_bindings::proc_,
+    va: c_bindings::uint64,
+) -> core::ffi::c_int {
+    if va >= c_bindings::MAXVA || va % MEGAPAGE_SIZE == 0 {
+        return 0;
+    }
+    match unsafe { split_megapage(&mut *proc, va) } {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+/// Look up a virtual address, return the physical address,
+/// or 0 if not mapped.
+/// Can only be used to look up user pages.
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+#[no_mangle]
+pub unsafe extern "C" fn walkaddr(
+    pagetable: c_bindings::pagetable_t,
+    va: c_bindings::uint64,
+) -> c_bindings::uint64 {
+    if va >= c_bindings::MAXVA {
+        return 0;
+    }
+    match unsafe { walk_leaf(pagetable, va) } {
+        Some((pte, page_size)) if pte.user_accessible() => {
+            pte.pa_int() + (PGROUNDDOWN!(va) & (page_size - 1))
+        }
+        _ => 0,
+    }
+}
+
+/// Remove npages of mappings starting from va. va must be
+/// page-aligned. Pages that were never mapped, like lazily
+/// allocated pages that were never touched, are skipped.
+/// Megapages must be removed whole.
+/// Optionally free the physical memory.
+/// Returns the number of pages unmapped that were in memory, rather than swapped out.
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+#[no_mangle]
+#[allow(clippy::missing_panics_doc)]
+pub unsafe extern "C" fn uvmunmap(
+    pagetable: c_bindings::pagetable_t,
+    va: c_bindings::uint64,
+    npages: c_bindings::uint64,
+    do_free: core::ffi::c_int,
+) -> c_bindings::uint64 {
+    if va % u64::from(c_bindings::PGSIZE) != 0 {
+        panic!("uvmunmap: not aligned\0");
+    }
+
+    let end = va + npages * u64::from(c_bindings::PGSIZE);
+    let mut a = va;
+    let mut unmapped = 0;
+    while a < end {
+        let step = match unsafe { walk_leaf(pagetable, a) } {
+            None => {
+                if let Some(pte) =
+                    unsafe { walk_level(pagetable, a, 0, false) }.filter(|pte| pte.swapped())
+                {
+                    if do_free != 0 {
+                        crate::swap::free_slot(pte.swap_slot());
+                    }
+                    *pte = PageTableEntry(0);
+                }
+                u64::from(c_bindings::PGSIZE)
+            }
+            Some((pte, page_size)) => {
+                if !pte.is_leaf() {
+                    panic!("uvmunmap: not a leaf\0");
+                }
+                if a % page_size != 0 || a + page_size > end {
+                    panic!("uvmunmap: part of a megapage\0");
+                }
+                if do_free != 0 {
+                    let layout = unsafe {
+                        Layout::from_size_align_unchecked(
+                            usize::try_from(page_size).unwrap(),
+                            usize::try_from(page_size).unwrap(),
+                        )
+                    };
+                    unsafe { alloc::alloc::dealloc(pte.pa_int() as *mut u8, layout) };
+                }
+                *pte = PageTableEntry(0);
+                unmapped += page_size / u64::from(c_bindings::PGSIZE);
+                page_size
+            }
+        };
+        a += step;
+    }
+    unmapped
+}
+
+/// Add a mapping of `size` bytes from `va` to `pa` to the kernel page table,
+/// using megapages wherever both addresses are aligned to them, and pages elsewhere.
+/// only used when booting.
+/// does not flush TLB or enable paging.
+/// # Safety
+/// Assumes that the page table passed in is a valid page table
+#[no_mangle]
+#[allow(clippy::missing_panics_doc)]
+pub unsafe extern "C" fn kvmmap_megapages(
+    kpgtbl: c_bindings::pagetable_t,
+    va: u64,
+    pa: u64,
+    size: u64,
+    perm: core::ffi::c_int,
+) {
+    let end = PGROUNDUP!(va + size);
+    let mut va = PGROUNDDOWN!(va);
+    let mut pa = PGROUNDDOWN!(pa);
+    while va < end {
+        let step =
+            if va % MEGAPAGE_SIZE == 0 && pa % MEGAPAGE_SIZE == 0 && end - va >= MEGAPAGE_SIZE {
+                match unsafe { walk_level(kpgtbl, va, 1, true) } {
+                    None => panic!("kvmmap_megapages\0"),
+                    Some(pte) if pte.valid() => panic!("kvmmap_megapages: remap\0"),
+                    Some(pte) => {
+                        *pte = PageTableEntry(
+                            u64::try_from(perm).unwrap() | u64::from(c_bindings::PTE_V),
+                        );
+                        pte.set_mapping(pa as *mut u8);
+                    }
+                }
+                MEGAPAGE_SIZE
+            } else {
+                unsafe { c_bindings::kvmmap(kpgtbl, va, pa, u64::from(c_bindings::PGSIZE), perm) };
+                u64::from(c_bindings::PGSIZE)
+            };
+        va += step;
+        pa += step;
     }
 }
 
@@ -150,32 +535,55 @@ unsafe fn vmprint_subtable(pagetable: c_bindings::pagetable_t, level: u16) {
 /// # Safety
 /// Assumes that the given page tables are valid page tables
 #[no_mangle]
-#[allow(clippy::missing_panics_doc)]
 pub unsafe extern "C" fn uvmcopy(
     old_pagetable: c_bindings::pagetable_t,
     new_pagetable: c_bindings::pagetable_t,
     size: u64,
 ) -> core::ffi::c_int {
-    for va in (0..size).step_by(c_bindings::PGSIZE as usize) {
-        match unsafe {
-            c_bindings::walk(old_pagetable, va, 0)
-                .cast::<PageTableEntry>()
-                .as_mut()
-        } {
-            None => {
-                panic!("uvmcopy: pte should exist\0");
-            }
-            Some(old_pte) => {
-                if !old_pte.valid() {
-                    panic!("uvmcopy: page not present");
+    unsafe { copy_pages(old_pagetable, new_pagetable, 0..size, false) }
+}
+
+/// Map the pages of `range` in the old page table into the new one.
+/// Writeable pages become COW pages in both, unless `shared` is set,
+/// in which case both page tables write to the same physical pages.
+/// returns 0 on success, -1 on failure.
+/// # Safety
+/// Assumes that the given page tables are valid page tables
+#[allow(clippy::missing_panics_doc)]
+pub(crate) unsafe fn copy_pages(
+    old_pagetable: c_bindings::pagetable_t,
+    new_pagetable: c_bindings::pagetable_t,
+    range: core::ops::Range<u64>,
+    shared: bool,
+) -> core::ffi::c_int {
+    // Lazily allocated pages the parent never touched stay unmapped in the child
+    for (va, level, old_pte) in unsafe { leaves(old_pagetable, range) }.with_swapped() {
+        match level {
+            // Swapped out pages share their swap slot, and are read in by each separately
+            0 if old_pte.swapped() => {
+                let Some(new_pte) = (unsafe { walk_level(new_pagetable, va, 0, true) }) else {
+                    return -1;
+                };
+                if crate::swap::dup_slot(old_pte.swap_slot()).is_none() {
+                    return -1;
                 }
-                let writeable_or_cow = old_pte.writeable() || old_pte.rsw() == RSW::COWPage;
+                *new_pte = *old_pte;
+            }
+            0 => {
+                let writeable_or_cow = !shared
+                    && !old_pte.shared_segment()
+                    && (old_pte.writeable() || old_pte.rsw() == RSW::COWPage);
                 if writeable_or_cow {
                     old_pte.set_rsw(RSW::COWPage);
                     old_pte.set_writeable(false);
                 }
                 let pa = old_pte.pa_int();
                 let flags = old_pte.get_flags();
+                let rsw = if writeable_or_cow {
+                    RSW::COWPage
+                } else {
+                    old_pte.rsw()
+                };
                 if unsafe {
                     c_bindings::mappages(
                         new_pagetable,
@@ -193,7 +601,7 @@ pub unsafe extern "C" fn uvmcopy(
                     return -1;
                 }
 
-                if writeable_or_cow {
+                if rsw != RSW::Default {
                     match unsafe {
                         c_bindings::walk(new_pagetable, va, 0)
                             .cast::<PageTableEntry>()
@@ -201,87 +609,433 @@ pub unsafe extern "C" fn uvmcopy(
                     } {
                         None => return -1,
                         Some(new_pte) => {
-                            new_pte.set_rsw(RSW::COWPage);
+                            new_pte.set_rsw(rsw);
                         }
                     }
                 }
                 ALLOCATOR.in_place_copy(usize::try_from(pa).unwrap());
             }
+            // Megapages only map whole, aligned parts of the heap
+            1 => {
+                if unsafe { copy_megapage(old_pte, new_pagetable, va, shared) } != 0 {
+                    return -1;
+                }
+            }
+            _ => panic!("copy_pages: gigapage\0"),
         }
     }
     0
 }
 
-/// Copy from kernel to user
-/// Copy len bytes from src to virtual address dstva in a given page table.
-/// Return 0 on sucess, -1 on error.
+/// Map the megapage of `old_pte` at `va` into the new page table too,
+/// becoming a COW megapage in both like [`copy_pages`] does for single pages.
+/// returns 0 on success, -1 on failure.
 /// # Safety
-/// Caller ensures that the data is in bounds, as needed
-#[no_mangle]
-#[allow(clippy::similar_names, clippy::missing_panics_doc)]
-pub unsafe extern "C" fn copyout(
-    pagetable: c_bindings::pagetable_t,
-    mut dstva: c_bindings::uint64,
-    mut src: *const u8,
-    mut len: c_bindings::uint64,
+/// Assumes that the new page table is a valid page table
+unsafe fn copy_megapage(
+    old_pte: &mut PageTableEntry,
+    new_pagetable: c_bindings::pagetable_t,
+    va: u64,
+    shared: bool,
 ) -> core::ffi::c_int {
-    while len > 0 {
-        let va0 = PGROUNDDOWN!(dstva);
-        if va0 >= c_bindings::MAXVA {
-            return -1;
+    let Some(new_pte) = (unsafe { walk_level(new_pagetable, va, 1, true) }) else {
+        return -1;
+    };
+    if new_pte.valid() {
+        panic!("copy_megapage: remap\0");
+    }
+    if !shared && (old_pte.writeable() || old_pte.rsw() == RSW::COWPage) {
+        old_pte.set_rsw(RSW::COWPage);
+        old_pte.set_writeable(false);
+    }
+    *new_pte = *old_pte;
+    // Every page holds its own reference, so the megapage can be split later
+    for page in (0..MEGAPAGE_SIZE).step_by(c_bindings::PGSIZE as usize) {
+        ALLOCATOR.in_place_copy(usize::try_from(old_pte.pa_int() + page).unwrap());
+    }
+    0
+}
+
+/// Give the COW page mapped by `pte` its write permission back, copying the page unless
+/// no other page table refers to it. The caller flushes the page's old translation.
+/// Returns whether the page was copied or reused, for [`crate::fault_stats`].
+/// Fails with `OutOfMemory`, leaving the page COW, if there was no page to copy it to
+/// # Safety
+/// `pte` must be a valid leaf PTE of a single page, with `RSW::COWPage` set
+#[allow(clippy::missing_panics_doc)]
+pub(crate) unsafe fn break_cow(pte: &mut PageTableEntry) -> Result<Fault, LazyAllocError> {
+    let fault = if ALLOCATOR.exactly_one_reference(usize::try_from(pte.pa_int()).unwrap()) {
+        Fault::CowReuse
+    } else {
+        Fault::CowCopy
+    };
+    if fault == Fault::CowCopy {
+        let page_size = c_bindings::PGSIZE as usize;
+        let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+        let new_page = unsafe { alloc::alloc::alloc(layout) };
+        if new_page.is_null() {
+            return Err(LazyAllocError::OutOfMemory);
         }
+        let old_page = pte.pa_int() as *mut u8;
+        unsafe { core::ptr::copy_nonoverlapping(old_page, new_page, page_size) };
+        pte.set_mapping(new_page);
+        unsafe { alloc::alloc::dealloc(old_page, layout) };
+    }
+    pte.set_rsw(RSW::Default);
+    pte.set_writeable(true);
+    Ok(fault)
+}
 
-        let pte = unsafe {
-            c_bindings::walk(pagetable, va0, 0)
-                .cast::<PageTableEntry>()
-                .as_mut()
-        };
-        match pte {
-            None => return -1,
-            Some(pte) => {
-                if !pte.valid() || !pte.user_accessible() {
-                    return -1;
-                }
-                // Do we need to cow this page?
-                if !pte.writeable() && pte.rsw() == RSW::COWPage {
-                    if ALLOCATOR.exactly_one_reference(usize::try_from(pte.pa_int()).unwrap()) {
-                        pte.set_rsw(RSW::COWPage);
-                        pte.set_writeable(true);
-                    } else {
-                        let old_pa = pte.pa_const().as_ptr();
-                        let page_size = c_bindings::PGSIZE as usize;
-                        let layout =
-                            unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
-                        let new_page = unsafe { alloc::alloc::alloc(layout) };
-                        // Out of memory, abort the copy
-                        if new_page.is_null() {
-                            return -1;
-                        }
-                        pte.set_writeable(true);
-                        pte.set_rsw(RSW::Default);
-                        // Map the page, and copy data to the COW'd page
-                        pte.set_mapping(new_page);
-                        unsafe { core::ptr::copy_nonoverlapping(old_pa, new_page, page_size) };
-                        unsafe { alloc::alloc::dealloc(old_pa.cast_mut(), layout) };
-                    }
-                }
+/// Why a fault on user memory could not be resolved by [`lazy_alloc`]
+pub(crate) enum LazyAllocError {
+    /// The address is outside of the process, or is already mapped
+    NotLazy,
+    /// No page could be allocated to back the address
+    OutOfMemory,
+}
 
-                let pa0 = pte.pa_int();
-                let mut n = c_bindings::PGSIZE as usize - usize::try_from(dstva - va0).unwrap();
-                if n > usize::try_from(len).unwrap() {
-                    n = usize::try_from(len).unwrap();
-                }
-                unsafe {
-                    core::ptr::copy(src, (pa0 + (dstva - va0)) as *mut u8, n);
-                }
+/// Back the page containing `va` with a zeroed page, if `sbrk` grew the process
+/// over it without mapping it. `size` is the size of the process owning `pagetable`
+/// # Safety
+/// Assumes that the given page table is a valid user page table
+pub(crate) unsafe fn lazy_alloc(
+    pagetable: c_bindings::pagetable_t,
+    size: u64,
+    va: u64,
+) -> Result<(), LazyAllocError> {
+    let va0 = PGROUNDDOWN!(va);
+    if va >= size || va0 >= c_bindings::MAXVA {
+        return Err(LazyAllocError::NotLazy);
+    }
+    // Guard pages are mapped, just not user accessible, so they are never lazily filled in
+    if unsafe {
+        c_bindings::walk(pagetable, va0, 0)
+            .cast::<PageTableEntry>()
+            .as_ref()
+    }
+    .is_some_and(PageTableEntry::valid)
+    {
+        return Err(LazyAllocError::NotLazy);
+    }
 
-                len -= u64::try_from(n).unwrap();
-                src = unsafe { src.offset(isize::try_from(n).unwrap()) };
-                dstva = va0 + u64::from(c_bindings::PGSIZE);
-            }
+    let page_size = c_bindings::PGSIZE as usize;
+    let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+    let page = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if page.is_null() {
+        return Err(LazyAllocError::OutOfMemory);
+    }
+    if unsafe {
+        c_bindings::mappages(
+            pagetable,
+            va0,
+            u64::from(c_bindings::PGSIZE),
+            page as u64,
+            i32::try_from(c_bindings::PTE_R | c_bindings::PTE_W | c_bindings::PTE_U).unwrap(),
+        )
+    } != 0
+    {
+        unsafe { alloc::alloc::dealloc(page, layout) };
+        return Err(LazyAllocError::OutOfMemory);
+    }
+    Ok(())
+}
+
+/// Map the page containing `va` if it was swapped out, or if `proc` reserved it without mapping it yet,
+/// either for its stack, by growing its heap, or with mmap. `write` is set for stores
+/// # Safety
+/// Assumes that `proc` has a valid page table
+pub(crate) unsafe fn demand_page(
+    proc: &mut c_bindings::proc_,
+    va: u64,
+    write: bool,
+) -> Result<(), LazyAllocError> {
+    if va < c_bindings::MAXVA
+        && unsafe { walk_level(proc.pagetable, va, 0, false) }.is_some_and(|pte| pte.swapped())
+    {
+        return unsafe { crate::swap::swap_in(proc, va) };
+    }
+    // Pages of the heap and stack are only mapped for accesses their protection allows
+    if va < proc.sz && !protection_allows(heap_protection(proc, va), write) {
+        return Err(LazyAllocError::NotLazy);
+    }
+    if (proc.stacklimit..proc.stackbottom).contains(&va) {
+        let top = proc.stackbottom;
+        let grown = unsafe { grow_stack(proc, va) };
+        unsafe { apply_heap_protection(proc, proc.stackbottom..top) };
+        // Pages may have been mapped even if the stack couldn't grow all the way
+        unsafe { proc_flush_tlb(proc) };
+        return grown;
+    }
+    if unsafe { lazy_alloc_megapage(proc, va) } {
+        add_resident(proc, MEGAPAGE_SIZE / u64::from(c_bindings::PGSIZE));
+        unsafe { proc_flush_tlb(proc) };
+        return Ok(());
+    }
+    let result = match unsafe { lazy_alloc(proc.pagetable, proc.sz, va) } {
+        Ok(()) => {
+            add_resident(proc, 1);
+            let va0 = PGROUNDDOWN!(va);
+            unsafe { apply_heap_protection(proc, va0..va0 + u64::from(c_bindings::PGSIZE)) };
+            Ok(())
         }
+        Err(LazyAllocError::NotLazy) => unsafe { crate::mmap::fault(proc, va, write) },
+        result => result,
+    };
+    // The TLB may hold the invalid PTE the page had before
+    if result.is_ok() {
+        flush_tlb_page(proc, va);
     }
-    0
+    result
+}
+
+/// Map a zeroed megapage around `va`, if the whole aligned megapage is in the heap of `proc`
+/// and none of it is mapped yet. Returns whether it was mapped, as single pages
+/// can still be mapped when memory is too fragmented for a megapage
+/// # Safety
+/// Assumes that `proc` has a valid page table
+unsafe fn lazy_alloc_megapage(proc: &c_bindings::proc_, va: u64) -> bool {
+    if va >= proc.sz {
+        return false;
+    }
+    let start = va & !(MEGAPAGE_SIZE - 1);
+    let end = start + MEGAPAGE_SIZE;
+    // The stack and its guard page stay single pages
+    let stack_start = proc
+        .stacklimit
+        .saturating_sub(u64::from(c_bindings::PGSIZE));
+    if end > proc.sz || (start < proc.stackbottom && stack_start < end) {
+        return false;
+    }
+    // So do parts of the heap with their own protection
+    if proc
+        .heapprots
+        .iter()
+        .any(|range| range.len > 0 && range.addr < end && start < range.addr + range.len)
+    {
+        return false;
+    }
+    if unsafe { walk_level(proc.pagetable, va, 1, false) }.is_some_and(|pte| pte.valid()) {
+        return false;
+    }
+
+    let megapage_size = usize::try_from(MEGAPAGE_SIZE).unwrap();
+    let layout = unsafe { Layout::from_size_align_unchecked(megapage_size, megapage_size) };
+    let megapage = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if megapage.is_null() {
+        return false;
+    }
+    let Some(pte) = (unsafe { walk_level(proc.pagetable, va, 1, true) }) else {
+        unsafe { alloc::alloc::dealloc(megapage, layout) };
+        return false;
+    };
+    *pte = PageTableEntry(u64::from(
+        c_bindings::PTE_R | c_bindings::PTE_W | c_bindings::PTE_U | c_bindings::PTE_V,
+    ));
+    pte.set_mapping(megapage);
+    true
+}
+
+/// Grow the user stack of `proc` down to the page containing `va`, which must be
+/// between the stack's limit and its lowest mapped page
+/// # Safety
+/// Assumes that `proc` has a valid page table
+unsafe fn grow_stack(proc: &mut c_bindings::proc_, va: u64) -> Result<(), LazyAllocError> {
+    // sbrk may have shrunk the process past the stack
+    if proc.stackbottom > proc.sz {
+        return Err(LazyAllocError::NotLazy);
+    }
+    let page_size = c_bindings::PGSIZE as usize;
+    let layout = unsafe { Layout::from_size_align_unchecked(page_size, page_size) };
+    // Map from the top down, so the stack always stays contiguous
+    while proc.stackbottom > PGROUNDDOWN!(va) {
+        let stack_page = proc.stackbottom - u64::from(c_bindings::PGSIZE);
+        let page = unsafe { alloc::alloc::alloc_zeroed(layout) };
+        if page.is_null() {
+            return Err(LazyAllocError::OutOfMemory);
+        }
+        if unsafe {
+            c_bindings::mappages(
+                proc.pagetable,
+                stack_page,
+                u64::from(c_bindings::PGSIZE),
+                page as u64,
+                i32::try_from(c_bindings::PTE_R | c_bindings::PTE_W | c_bindings::PTE_U).unwrap(),
+            )
+        } != 0
+        {
+            unsafe { alloc::alloc::dealloc(page, layout) };
+            return Err(LazyAllocError::OutOfMemory);
+        }
+        add_resident(proc, 1);
+        proc.stackbottom = stack_page;
+    }
+    Ok(())
+}
+
+/// Change the protection of the pages of `proc` in `[addr, addr + len)` to `protection`, made of the
+/// `PROT_` flags. The range must be below the size of the process, or cover exactly one region mapped
+/// with mmap. Only pages already mapped or swapped out are changed. The rest get `protection` when
+/// they are faulted in, as it is recorded for the region, or for that part of the heap.
+/// Returns None if the range or protection is invalid, if there is no room to record a
+/// protection for the heap, or if memory ran out
+/// # Safety
+/// Assumes that `proc` has a valid page table
+pub(crate) unsafe fn mprotect(
+    proc: &mut c_bindings::proc_,
+    addr: u64,
+    len: u64,
+    protection: i32,
+) -> Option<()> {
+    let end = addr.checked_add(PGROUNDUP!(len))?;
+    let known_flags = c_bindings::PROT_READ | c_bindings::PROT_WRITE | c_bindings::PROT_EXEC;
+    if addr % u64::from(c_bindings::PGSIZE) != 0
+        || len == 0
+        || u32::try_from(protection).ok()? & !known_flags != 0
+    {
+        return None;
+    }
+    let in_heap = end <= proc.sz;
+    let shared = if in_heap {
+        false
+    } else {
+        crate::mmap::protect(proc, addr..end, protection)?
+    };
+
+    for (va, level, pte) in unsafe { leaves(proc.pagetable, addr..end) } {
+        // Guard pages are mapped, but were never accessible to the user
+        if level == 0 && !pte.user_accessible() && pte.rsw() == RSW::Default {
+            return None;
+        }
+        // Protection is changed one page at a time
+        if level > 0 {
+            unsafe { split_megapage(proc, va) }?;
+        }
+    }
+    if in_heap {
+        record_heap_protection(proc, addr..end, protection)?;
+    }
+
+    for (_, _, pte) in unsafe { leaves(proc.pagetable, addr..end) }.with_swapped() {
+        protect_page(pte, protection, shared);
+    }
+    unsafe { proc_flush_tlb(proc) };
+    Some(())
+}
+
+/// Give a user page, mapped or swapped out, the protection `prot`. The markers in its RSW field
+/// are kept, so COW pages stay COW, to be copied by a store fault once `prot` allows writing.
+/// Pages that other page tables may still map become COW pages when write permission is
+/// granted back, unless they belong to a `shared` mapping
+fn protect_page(pte: &mut PageTableEntry, prot: i32, shared: bool) {
+    let perm = pte_permissions(prot);
+    if perm == 0 {
+        // A valid PTE without R, W or X points to another page table,
+        // so the page stays readable, just not by the user
+        pte.set_user_accessible(false);
+        pte.set_readable(true);
+        pte.set_writeable(false);
+        pte.set_executable(false);
+        // Marked to tell it apart from a guard page, unless its marker already does
+        if pte.rsw() == RSW::Default {
+            pte.set_rsw(RSW::NoAccess);
+        }
+        return;
+    }
+
+    if pte.rsw() == RSW::NoAccess {
+        pte.set_rsw(RSW::Default);
+    }
+    pte.set_user_accessible(true);
+    pte.set_readable(perm & c_bindings::PTE_R != 0);
+    pte.set_executable(perm & c_bindings::PTE_X != 0);
+    if perm & c_bindings::PTE_W == 0 || pte.rsw() == RSW::COWPage {
+        pte.set_writeable(false);
+    } else if pte.valid()
+        && !pte.writeable()
+        && !shared
+        && !ALLOCATOR.exactly_one_reference(usize::try_from(pte.pa_int()).unwrap())
+    {
+        pte.set_rsw(RSW::COWPage);
+    } else {
+        pte.set_writeable(true);
+    }
+}
+
+/// Protection of the parts of the heap mprotect recorded nothing for
+const HEAP_PROTECTION: u32 = c_bindings::PROT_READ | c_bindings::PROT_WRITE;
+
+/// The protection of the page of `proc`'s heap or stack at `va`, made of the `PROT_` flags
+fn heap_protection(proc: &c_bindings::proc_, va: u64) -> i32 {
+    proc.heapprots
+        .iter()
+        .find(|range| range.len > 0 && range.addr <= va && va < range.addr + range.len)
+        .map_or(i32::try_from(HEAP_PROTECTION).unwrap(), |range| range.prot)
+}
+
+/// Record that the pages of `proc`'s heap in `range` get `protection` when faulted in,
+/// replacing whatever was recorded for them before.
+/// Returns None, recording nothing, if there is no room left to record it
+fn record_heap_protection(
+    proc: &mut c_bindings::proc_,
+    range: core::ops::Range<u64>,
+    protection: i32,
+) -> Option<()> {
+    let mut ranges = proc.heapprots;
+    // What is left of a recorded range that `range` falls in the middle of
+    let mut rest = None;
+    for recorded in ranges.iter_mut().filter(|recorded| recorded.len > 0) {
+        let end = recorded.addr + recorded.len;
+        if end <= range.start || range.end <= recorded.addr {
+            continue;
+        }
+        let after = (range.end < end).then_some(c_bindings::heapprot {
+            addr: range.end,
+            len: end - range.end,
+            prot: recorded.prot,
+        });
+        if recorded.addr < range.start {
+            recorded.len = range.start - recorded.addr;
+            rest = after;
+        } else if let Some(after) = after {
+            *recorded = after;
+        } else {
+            recorded.len = 0;
+        }
+    }
+    let added =
+        (protection != i32::try_from(HEAP_PROTECTION).unwrap()).then_some(c_bindings::heapprot {
+            addr: range.start,
+            len: range.end - range.start,
+            prot: protection,
+        });
+    for new in rest.into_iter().chain(added) {
+        *ranges.iter_mut().find(|unused| unused.len == 0)? = new;
+    }
+    proc.heapprots = ranges;
+    Some(())
+}
+
+/// Give the pages just mapped in `range` of `proc`'s heap or stack the protection recorded for them
+/// # Safety
+/// Assumes that `proc` has a valid page table
+unsafe fn apply_heap_protection(proc: &c_bindings::proc_, range: core::ops::Range<u64>) {
+    for (va, _, pte) in unsafe { leaves(proc.pagetable, range) } {
+        let protection = heap_protection(proc, va);
+        if protection != i32::try_from(HEAP_PROTECTION).unwrap() {
+            protect_page(pte, protection, false);
+        }
+    }
+}
+
+/// Whether the user may write to the page of `proc` at `va`, going by the protection of the
+/// region mapped there, or else of its heap. COW pages are only copied if so
+pub(crate) fn writeable_by_user(proc: &c_bindings::proc_, va: u64) -> bool {
+    let protection = proc
+        .vmas
+        .iter()
+        .find(|vma| vma.len > 0 && vma.addr <= va && va < vma.addr + vma.len)
+        .map_or_else(|| heap_protection(proc, va), |vma| vma.prot);
+    has_flag(protection, c_bindings::PROT_WRITE)
 }
 
 macro_rules! PGROUNDUP {
@@ -134,6 +134,12 @@ static uint64 (*syscalls[])(void) = {
 [SYS_pgdirty]  sys_pgdirty,
 [SYS_sigalarm] sys_sigalarm,
 [SYS_sigreturn] sys_sigreturn,
+[SYS_mmap]     sys_mmap,
+[SYS_munmap]   sys_munmap,
+[SYS_mprotect] sys_mprotect,
+[SYS_shmat]    sys_shmat,
+[SYS_shmdt]    sys_shmdt,
+[SYS_faultstats] sys_faultstats,
 };
 
 static char* syscall_names[] = {
@@ -165,6 +171,12 @@ static char* syscall_names[] = {
 [SYS_pgdirty]   "pgdirty",
 [SYS_sigalarm]  "sigalarm",
 [SYS_sigreturn] "sigreturn",
+[SYS_mmap]      "mmap",
+[SYS_munmap]    "munmap",
+[SYS_mprotect]  "mprotect",
+[SYS_shmat]     "shmat",
+[SYS_shmdt]     "shmdt",
+[SYS_faultstats] "faultstats",
 };
 
 void
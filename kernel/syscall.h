@@ -29,4 +29,10 @@
 #define SYS_pgdirty   26
 #define SYS_sigalarm  27
 #define SYS_sigreturn 28
+#define SYS_mmap      29
+#define SYS_munmap    30
+#define SYS_mprotect  31
+#define SYS_shmat     32
+#define SYS_shmdt     33
+#define SYS_faultstats 34
 #endif // SYSCALL_H
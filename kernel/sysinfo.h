@@ -1,10 +1,24 @@
 #ifndef SYSINFO_H
 #define SYSINFO_H
 #include "types.h"
+#include "param.h"
+// Page faults, of one process or the whole system
+struct faultstats {
+  uint64 minor;      // Faults that mapped a page that was reserved or swapped out
+  uint64 cow_copies; // Writes to COW pages that copied the page
+  uint64 cow_reuses; // Writes to COW pages with no other reference, which kept the page
+  uint64 fatal;      // Faults on bad addresses, which killed the process
+};
 struct sysinfo {
   uint64 max_mem;
   uint64 cpu_count;
   uint64 freemem;
   uint64 nproc;
+  uint64 page_cache_hits[NCPU];   // Page allocations served by each hart's page cache
+  uint64 page_cache_misses[NCPU]; // Page allocations that went to the global freelist
+  uint64 free_pages;              // Free physical pages
+  uint64 low_watermark;           // Below this many free pages, memory pressure is reported
+  uint64 min_watermark;           // Below this many free pages, the OOM killer runs
+  struct faultstats faults;       // Page faults since boot
 };
 #endif // SYSINFO_H
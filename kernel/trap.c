@@ -61,14 +61,17 @@ usertrapret(uint64 new_sepc)
     w_sepc(p->trapframe->epc);
   }
 
-  // tell trampoline.S the user page table to switch to.
-  uint64 satp = MAKE_SATP(p->pagetable);
+  // tell trampoline.S the user page table to switch to,
+  // and whether the TLB entries of its ASID have to be flushed.
+  int flush;
+  uint64 asid = proc_asid(p, &flush);
+  uint64 satp = MAKE_SATP_ASID(p->pagetable, asid);
 
   // jump to userret in trampoline.S at the top of memory, which 
   // switches to the user page table, restores user registers,
   // and switches to user mode with sret.
   uint64 trampoline_userret = TRAMPOLINE + (userret - trampoline);
-  ((void (*)(uint64))trampoline_userret)(satp);
+  ((void (*)(uint64, uint64, uint64))trampoline_userret)(satp, asid, flush);
 }
 
 // interrupts and exceptions from kernel code go here via kernelvec,
@@ -77,6 +80,7 @@ void
 kerneltrap()
 {
   int which_dev = 0;
+  uint64 fixup;
   uint64 sepc = r_sepc();
   uint64 sstatus = r_sstatus();
   uint64 scause = r_scause();
@@ -86,7 +90,14 @@ kerneltrap()
   if(intr_get() != 0)
     panic("kerneltrap: interrupts enabled");
 
-  if((which_dev = devintr()) == 0 || which_dev == 3){
+  which_dev = devintr();
+  if((scause == 13 || scause == 15 || scause == 5 || scause == 7) &&
+     (fixup = exception_fixup(sepc)) != 0){
+    // a load or store fault copying to or from user memory makes
+    // the copy return -1, from the fixup the exception table gives
+    // for the faulting instruction. any other fault is a kernel bug.
+    sepc = fixup;
+  } else if(which_dev == 0 || which_dev == 3 || which_dev == 4){
     printf("scause %p\n", scause);
     printf("sepc =%p stval=%p\n", r_sepc(), r_stval());
     printf("etext=%p max  =%p\n", etext, PHYSICAL_ADDRESS_STOP);
@@ -105,7 +116,8 @@ kerneltrap()
 
 // check if it's an external interrupt or software interrupt,
 // and handle it.
-// returns 3 if write page fault,
+// returns 4 if read page fault,
+// 3 if write page fault,
 // 2 if timer interrupt,
 // 1 if other device,
 // 0 if not recognized.
@@ -152,6 +164,10 @@ devintr()
   } else if (scause == 15){
     // This is a write page fault
     return 3;
+  } else if (scause == 13 || scause == 12){
+    // This is a read page fault, or an instruction page
+    // fault, which are both resolved by mapping the page
+    return 4;
   } else {
     return 0;
   }
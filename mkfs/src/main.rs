@@ -76,7 +76,8 @@ fn main() {
 
     println!("nmeta {nmeta} (boot, super, log blocks {nlog} inode blocks {ninodeblocks}, bitmap blocks {nbitmap}) blocks {nblocks} total {}", c_bindings::FSSIZE);
     let mut freeblock = u32::try_from(nmeta).unwrap();
-    disk.set_len((c_bindings::FSSIZE * c_bindings::BSIZE) as u64)
+    // The swap area follows the file system
+    disk.set_len(((c_bindings::FSSIZE + c_bindings::SWAPSIZE) * c_bindings::BSIZE) as u64)
         .unwrap();
     {
         let mut superblock_data = [0u8; c_bindings::BSIZE as usize];
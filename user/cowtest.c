@@ -178,6 +178,118 @@ filetest()
   printf("ok\n");
 }
 
+#define FAULTPAGES 10
+
+// check that COW faults are counted, both for the process
+// taking them and system-wide.
+void
+faultstest()
+{
+  printf("faults: ");
+
+  struct faultstats before, after;
+  struct sysinfo info;
+  char *p = sbrk(FAULTPAGES*4096);
+  if(p == (char*)0xffffffffffffffffL){
+    printf("sbrk failed\n");
+    exit(-1);
+  }
+  if(faultstats(getpid(), &before) != 0){
+    printf("faultstats failed\n");
+    exit(-1);
+  }
+  for(int i = 0; i < FAULTPAGES; i++)
+    p[i*4096] = 1;
+  if(faultstats(getpid(), &after) != 0){
+    printf("faultstats failed\n");
+    exit(-1);
+  }
+  // untouched heap is mapped on first access, maybe a megapage at a time.
+  if(after.minor <= before.minor){
+    printf("error: no minor faults counted\n");
+    exit(1);
+  }
+
+  int pid = fork();
+  if(pid < 0){
+    printf("fork failed\n");
+    exit(-1);
+  }
+  if(pid == 0){
+    if(faultstats(getpid(), &before) != 0)
+      exit(-1);
+    for(int i = 0; i < FAULTPAGES; i++)
+      p[i*4096] = 2;
+    if(faultstats(getpid(), &after) != 0)
+      exit(-1);
+    if(after.cow_copies - before.cow_copies < FAULTPAGES){
+      printf("error: %d COW copies counted, not %d\n",
+             after.cow_copies - before.cow_copies, FAULTPAGES);
+      exit(1);
+    }
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+
+  // the child has exited, so the parent's pages are only its own.
+  if(faultstats(getpid(), &before) != 0){
+    printf("faultstats failed\n");
+    exit(-1);
+  }
+  for(int i = 0; i < FAULTPAGES; i++)
+    p[i*4096] = 3;
+  if(faultstats(getpid(), &after) != 0){
+    printf("faultstats failed\n");
+    exit(-1);
+  }
+  if(after.cow_reuses - before.cow_reuses < FAULTPAGES || after.cow_copies != before.cow_copies){
+    printf("error: writing unshared COW pages counted %d reuses and %d copies\n",
+           after.cow_reuses - before.cow_reuses, after.cow_copies - before.cow_copies);
+    exit(1);
+  }
+
+  // a child killed by a bad access counts a fatal fault.
+  if(sysinfo(&info) != 0){
+    printf("sysinfo failed\n");
+    exit(-1);
+  }
+  uint64 fatal = info.faults.fatal;
+  pid = fork();
+  if(pid < 0){
+    printf("fork failed\n");
+    exit(-1);
+  }
+  if(pid == 0){
+    *(volatile char *)0 = 1;
+    exit(0);
+  }
+  wait(&xstatus);
+  if(xstatus != -1 || sysinfo(&info) != 0 || info.faults.fatal <= fatal){
+    printf("error: fatal fault not counted\n");
+    exit(1);
+  }
+
+  // the child has been waited for, so is gone.
+  if(faultstats(pid, &after) != -1){
+    printf("error: faultstats of a freed process succeeded\n");
+    exit(1);
+  }
+  if(faultstats(getpid(), (struct faultstats *) 0xeaeb0b5b00002f5e) != -1){
+    printf("error: faultstats succeeded with bad argument\n");
+    exit(1);
+  }
+
+  if(sbrk(-FAULTPAGES*4096) == (char*)0xffffffffffffffffL){
+    printf("sbrk failed\n");
+    exit(-1);
+  }
+
+  printf("ok\n");
+}
+
 int
 main(int argc, char *argv[])
 {
@@ -198,6 +310,8 @@ main(int argc, char *argv[])
 
   filetest();
 
+  faultstest();
+
   printf("ALL COW TESTS PASSED\n");
 
   exit(0);
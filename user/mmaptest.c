@@ -0,0 +1,304 @@
+//
+// tests for mmap and munmap.
+//
+
+#include "kernel/types.h"
+#include "kernel/riscv.h"
+#include "kernel/fcntl.h"
+#include "user/user.h"
+
+#define MAP_FAILED ((char *) 0xffffffffffffffffL)
+
+// not a whole number of pages, so the last
+// mapped page runs past the end of the file.
+#define FILESIZE (PGSIZE + PGSIZE/2)
+
+char *testname = "???";
+
+void
+err(char *why)
+{
+  printf("mmaptest: %s failed: %s, pid=%d\n", testname, why, getpid());
+  exit(1);
+}
+
+// create a file of FILESIZE bytes, where byte i is 'A' + i % 23.
+void
+makefile(const char *f)
+{
+  char buf[PGSIZE/2];
+
+  unlink(f);
+  int fd = open(f, O_WRONLY | O_CREATE);
+  if(fd < 0)
+    err("open");
+  for(int i = 0; i < FILESIZE; i += sizeof(buf)){
+    for(int j = 0; j < sizeof(buf); j++)
+      buf[j] = 'A' + (i + j) % 23;
+    if(write(fd, buf, sizeof(buf)) != sizeof(buf))
+      err("write");
+  }
+  close(fd);
+}
+
+// check that the file's contents are what makefile() wrote,
+// except for the bytes in [changed, changed+nchanged),
+// which should all be c.
+void
+checkfile(const char *f, int changed, int nchanged, char c)
+{
+  char buf[PGSIZE/2];
+
+  int fd = open(f, O_RDONLY);
+  if(fd < 0)
+    err("open");
+  for(int i = 0; i < FILESIZE; i += sizeof(buf)){
+    if(read(fd, buf, sizeof(buf)) != sizeof(buf))
+      err("read");
+    for(int j = 0; j < sizeof(buf); j++){
+      char want = 'A' + (i + j) % 23;
+      if(i + j >= changed && i + j < changed + nchanged)
+        want = c;
+      if(buf[j] != want)
+        err("wrong file contents");
+    }
+  }
+  // writing back pages never grows the file.
+  if(read(fd, buf, sizeof(buf)) != 0)
+    err("file grew");
+  close(fd);
+}
+
+// anonymous memory is zeroed, private, and goes away on munmap.
+void
+anontest()
+{
+  testname = "anon";
+  printf("%s: ", testname);
+
+  char *p = mmap(0, 3*PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if(p == MAP_FAILED)
+    err("mmap");
+  for(int i = 0; i < 3*PGSIZE; i++)
+    if(p[i] != 0)
+      err("not zeroed");
+  for(int i = 0; i < 3*PGSIZE; i++)
+    p[i] = i % 251;
+  for(int i = 0; i < 3*PGSIZE; i++)
+    if(p[i] != i % 251)
+      err("wrong value");
+
+  // unmap from both ends.
+  if(munmap(p, PGSIZE) != 0)
+    err("munmap start");
+  if(munmap(p + 2*PGSIZE, PGSIZE) != 0)
+    err("munmap end");
+  if(p[PGSIZE] != PGSIZE % 251)
+    err("middle page lost");
+  if(munmap(p + PGSIZE, PGSIZE) != 0)
+    err("munmap middle");
+
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    p[PGSIZE] = 1;
+    // the store should have killed us.
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != -1)
+    err("unmapped memory still accessible");
+
+  printf("OK\n");
+}
+
+// a shared file mapping is read in from the file,
+// and stores to it are written back.
+void
+sharedfiletest()
+{
+  const char *f = "mmap.shared";
+  testname = "shared file";
+  printf("%s: ", testname);
+
+  makefile(f);
+  int fd = open(f, O_RDWR);
+  if(fd < 0)
+    err("open");
+  char *p = mmap(0, 2*PGSIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0);
+  if(p == MAP_FAILED)
+    err("mmap");
+  // the mapping holds its own reference to the file.
+  close(fd);
+
+  for(int i = 0; i < FILESIZE; i++)
+    if(p[i] != 'A' + i % 23)
+      err("wrong mapped contents");
+  for(int i = FILESIZE; i < 2*PGSIZE; i++)
+    if(p[i] != 0)
+      err("past end of file not zeroed");
+
+  for(int i = 100; i < 200; i++)
+    p[i] = 'z';
+  for(int i = PGSIZE + 10; i < PGSIZE + 20; i++)
+    p[i] = 'z';
+  // and past the end of the file, which is not written back.
+  p[2*PGSIZE - 1] = 'z';
+  if(munmap(p, PGSIZE) != 0)
+    err("munmap");
+  checkfile(f, 100, 100, 'z');
+  if(munmap(p + PGSIZE, PGSIZE) != 0)
+    err("munmap");
+
+  fd = open(f, O_RDONLY);
+  if(fd < 0)
+    err("open");
+  char buf[100];
+  if(read(fd, buf, 100) != 100 || read(fd, buf, 100) != 100)
+    err("read");
+  for(int i = 0; i < 100; i++)
+    if(buf[i] != 'z')
+      err("first page not written back");
+  close(fd);
+
+  unlink(f);
+  printf("OK\n");
+}
+
+// stores to a private file mapping never reach the file,
+// and the file's permissions are respected.
+void
+privatefiletest()
+{
+  const char *f = "mmap.private";
+  testname = "private file";
+  printf("%s: ", testname);
+
+  makefile(f);
+  int fd = open(f, O_RDONLY);
+  if(fd < 0)
+    err("open");
+  if(mmap(0, PGSIZE, PROT_READ | PROT_WRITE, MAP_SHARED, fd, 0) != MAP_FAILED)
+    err("writeable shared mapping of a read-only file");
+  if(mmap(0, PGSIZE, PROT_READ, MAP_PRIVATE, fd, 1) != MAP_FAILED)
+    err("mapping at an unaligned offset");
+  if(mmap(0, 2*PGSIZE, PROT_READ, MAP_PRIVATE, fd, 0xfffff000) != MAP_FAILED)
+    err("mapping past the largest file offset");
+  char *p = mmap(0, 2*PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+  if(p == MAP_FAILED)
+    err("mmap");
+  close(fd);
+
+  for(int i = 0; i < FILESIZE; i++)
+    p[i] = 'z';
+  if(munmap(p, 2*PGSIZE) != 0)
+    err("munmap");
+  checkfile(f, 0, 0, 0);
+
+  unlink(f);
+  printf("OK\n");
+}
+
+// a read-only mapping can't be written, by the process or the kernel.
+void
+readonlytest()
+{
+  const char *f = "mmap.readonly";
+  testname = "read-only";
+  printf("%s: ", testname);
+
+  makefile(f);
+  int fd = open(f, O_RDWR);
+  if(fd < 0)
+    err("open");
+  char *p = mmap(0, PGSIZE, PROT_READ, MAP_SHARED, fd, 0);
+  if(p == MAP_FAILED)
+    err("mmap");
+  if(p[0] != 'A')
+    err("wrong mapped contents");
+  // read() into the mapping has to fail.
+  if(read(fd, p, 10) != -1)
+    err("read into read-only mapping");
+
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    p[0] = 'z';
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != -1)
+    err("store to read-only mapping");
+
+  close(fd);
+  if(munmap(p, PGSIZE) != 0)
+    err("munmap");
+  checkfile(f, 0, 0, 0);
+  unlink(f);
+  printf("OK\n");
+}
+
+// a child shares MAP_SHARED pages with its parent,
+// but gets its own copy of MAP_PRIVATE pages.
+void
+forktest()
+{
+  testname = "fork";
+  printf("%s: ", testname);
+
+  char *shared = mmap(0, 2*PGSIZE, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_ANONYMOUS, -1, 0);
+  char *private = mmap(0, 2*PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if(shared == MAP_FAILED || private == MAP_FAILED)
+    err("mmap");
+  shared[0] = 'p';
+  private[0] = 'p';
+
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    if(shared[0] != 'p' || private[0] != 'p')
+      err("child sees wrong values");
+    shared[0] = 'c';
+    private[0] = 'c';
+    // the second pages were never touched before the fork.
+    shared[PGSIZE] = 'c';
+    private[PGSIZE] = 'c';
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+
+  if(shared[0] != 'c')
+    err("shared page not shared");
+  if(private[0] != 'p')
+    err("private page shared");
+  if(shared[PGSIZE] != 'c')
+    err("untouched shared page not shared");
+  if(private[PGSIZE] != 0)
+    err("untouched private page shared");
+
+  if(munmap(shared, 2*PGSIZE) != 0 || munmap(private, 2*PGSIZE) != 0)
+    err("munmap");
+  printf("OK\n");
+}
+
+int
+main(int argc, char *argv[])
+{
+  anontest();
+  sharedfiletest();
+  privatefiletest();
+  readonlytest();
+  forktest();
+
+  printf("ALL MMAP TESTS PASSED\n");
+
+  exit(0);
+}
@@ -0,0 +1,308 @@
+//
+// tests for mprotect.
+//
+
+#include "kernel/types.h"
+#include "kernel/riscv.h"
+#include "kernel/fcntl.h"
+#include "user/user.h"
+
+#define MAP_FAILED ((char *) 0xffffffffffffffffL)
+
+char *testname = "???";
+
+void
+err(char *why)
+{
+  printf("mprotecttest: %s failed: %s, pid=%d\n", testname, why, getpid());
+  exit(1);
+}
+
+// sbrk a fresh, page aligned page.
+char *
+newpage()
+{
+  char *p = sbrk(0);
+  sbrk(PGROUNDUP((uint64) p) - (uint64) p);
+  p = sbrk(PGSIZE);
+  if(p == MAP_FAILED)
+    err("sbrk");
+  return p;
+}
+
+// run f in a child, and check whether the child was killed.
+void
+expectkill(void (*f)(char *), char *p, int killed, char *why)
+{
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    f(p);
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if((xstatus == -1) != killed)
+    err(why);
+}
+
+void
+store(char *p)
+{
+  *(volatile char *)p = 'x';
+}
+
+void
+load(char *p)
+{
+  if(*(volatile char *)p == 'x')
+    exit(1);
+}
+
+// write "li a0, value; ret" to code.
+void
+emit(uint *code, int value)
+{
+  code[0] = 0x00000513 | (value << 20);
+  code[1] = 0x00008067;
+  // fence.i, spelled out for assemblers without zifencei,
+  // so the new instructions are fetched.
+  asm volatile(".word 0x0000100f" ::: "memory");
+}
+
+// W^X: write code to a page, flip it to executable, and run it.
+void
+jittest()
+{
+  testname = "jit";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  emit((uint *) p, 42);
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_EXEC) != 0)
+    err("mprotect rx");
+  int (*f)(void) = (int (*)(void)) p;
+  if(f() != 42)
+    err("wrong return value");
+  expectkill(store, p, 1, "store to executable page");
+
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+    err("mprotect rw");
+  emit((uint *) p, 7);
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_EXEC) != 0)
+    err("mprotect rx");
+  if(f() != 7)
+    err("wrong return value after rewrite");
+
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+    err("mprotect rw");
+  printf("OK\n");
+}
+
+// a page shared copy-on-write with a child stays
+// copy-on-write when write permission comes back.
+void
+cowtest()
+{
+  testname = "cow";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  p[0] = 'p';
+
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    if(mprotect(p, PGSIZE, PROT_READ) != 0)
+      err("mprotect r");
+    if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+      err("mprotect rw");
+    p[0] = 'c';
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+  if(p[0] != 'p')
+    err("child wrote to the parent's page");
+
+  printf("OK\n");
+}
+
+// a copy-on-write page made read-only stays copy-on-write,
+// and can't be written by the process or the kernel.
+void
+cowreadonlytest()
+{
+  int fds[2];
+
+  testname = "cow read-only";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  p[0] = 'p';
+
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    if(mprotect(p, PGSIZE, PROT_READ) != 0)
+      err("mprotect r");
+    expectkill(store, p, 1, "store to read-only COW page");
+    if(pipe(fds) != 0)
+      err("pipe");
+    if(write(fds[1], "c", 1) != 1)
+      err("write");
+    if(read(fds[0], p, 1) != -1)
+      err("read into read-only COW page");
+    if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+      err("mprotect rw");
+    p[0] = 'c';
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+  if(p[0] != 'p')
+    err("child wrote to the parent's page");
+
+  printf("OK\n");
+}
+
+// PROT_NONE pages can't be touched, but keep their contents.
+void
+nonetest()
+{
+  testname = "none";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  p[0] = 'a';
+  if(mprotect(p, PGSIZE, PROT_NONE) != 0)
+    err("mprotect none");
+  expectkill(load, p, 1, "load from PROT_NONE page");
+  // the kernel can't read it either.
+  if(write(1, p, 1) == 1)
+    err("write from PROT_NONE page");
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+    err("mprotect rw");
+  if(p[0] != 'a')
+    err("contents lost");
+
+  printf("OK\n");
+}
+
+// lazily allocated pages keep the protection they were given.
+void
+lazytest()
+{
+  testname = "lazy";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  if(mprotect(p, PGSIZE, PROT_READ) != 0)
+    err("mprotect r");
+  expectkill(load, p, 0, "load from read-only page");
+  expectkill(store, p, 1, "store to read-only page");
+  if(mprotect(p, PGSIZE, PROT_READ | PROT_WRITE) != 0)
+    err("mprotect rw");
+
+  printf("OK\n");
+}
+
+// untouched heap pages are left unmapped, and get the
+// protection when they are faulted in.
+void
+largetest()
+{
+  struct sysinfo before, after;
+
+  testname = "large";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  if(sbrk(1023*PGSIZE) == MAP_FAILED)
+    err("sbrk");
+  if(sysinfo(&before) < 0)
+    err("sysinfo");
+  if(mprotect(p, 1024*PGSIZE, PROT_READ) != 0)
+    err("mprotect r");
+  if(sysinfo(&after) < 0)
+    err("sysinfo");
+  if(after.free_pages + 16 < before.free_pages)
+    err("untouched pages were mapped");
+  expectkill(load, p + 512*PGSIZE, 0, "load from read-only page");
+  expectkill(store, p + 513*PGSIZE, 1, "store to read-only page");
+  if(mprotect(p, 1024*PGSIZE, PROT_READ | PROT_WRITE) != 0)
+    err("mprotect rw");
+  p[514*PGSIZE] = 'x';
+  if(sbrk(-1024*PGSIZE) == MAP_FAILED)
+    err("sbrk shrink");
+
+  printf("OK\n");
+}
+
+// mmap regions take on the new protection, even for pages not faulted in yet.
+void
+mmaptest()
+{
+  testname = "mmap";
+  printf("%s: ", testname);
+
+  char *p = mmap(0, 2*PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if(p == MAP_FAILED)
+    err("mmap");
+  p[0] = 'a';
+  if(mprotect(p, PGSIZE, PROT_READ) != -1)
+    err("mprotect of part of a mapping");
+  if(mprotect(p, 2*PGSIZE, PROT_READ) != 0)
+    err("mprotect r");
+  expectkill(store, p, 1, "store to read-only mapping");
+  expectkill(store, p + PGSIZE, 1, "store to untouched read-only mapping");
+  if(munmap(p, 2*PGSIZE) != 0)
+    err("munmap");
+
+  printf("OK\n");
+}
+
+// bad arguments are rejected.
+void
+argstest()
+{
+  testname = "args";
+  printf("%s: ", testname);
+
+  char *p = newpage();
+  if(mprotect(p + 1, PGSIZE, PROT_READ) != -1)
+    err("unaligned address");
+  if(mprotect(p, PGSIZE, 0x100) != -1)
+    err("unknown flag");
+  if(mprotect(p + PGSIZE, PGSIZE, PROT_READ) != -1)
+    err("past the end of memory");
+  // exec leaves page 0 unmapped for the user, to catch null pointers.
+  if(mprotect(0, PGSIZE, PROT_READ) != -1)
+    err("null page");
+
+  printf("OK\n");
+}
+
+int
+main(int argc, char *argv[])
+{
+  jittest();
+  cowtest();
+  cowreadonlytest();
+  nonetest();
+  lazytest();
+  largetest();
+  mmaptest();
+  argstest();
+
+  printf("ALL MPROTECT TESTS PASSED\n");
+
+  exit(0);
+}
@@ -6,12 +6,14 @@
 
 void ugetpid_test();
 void pgaccess_test();
+void pgbits_test();
 
 int
 main(int argc, char *argv[])
 {
   ugetpid_test();
   pgaccess_test();
+  pgbits_test();
   printf("pgtbltest: all tests succeeded\n");
   exit(0);
 }
@@ -56,15 +58,83 @@ pgaccess_test()
   printf("pgaccess_test starting\n");
   testname = "pgaccess_test";
   buf = malloc(32 * PGSIZE);
-  if (pgaccess(buf, 32, &abits) < 0)
+  if (pgaccess(buf, 32, &abits, 0) < 0)
     err("pgaccess failed");
   buf[PGSIZE * 1] += 1;
   buf[PGSIZE * 2] += 1;
   buf[PGSIZE * 30] += 1;
-  if (pgaccess(buf, 32, &abits) < 0)
+  if (pgaccess(buf, 32, &abits, 0) < 0)
     err("pgaccess failed");
   if (abits != ((1 << 1) | (1 << 2) | (1 << 30)))
     err("incorrect access bits set");
   free(buf);
   printf("pgaccess_test: OK\n");
 }
+
+#define NBITPAGES 1000
+
+int
+bitset(uchar *bits, int page)
+{
+  return (bits[page / 8] >> (page % 8)) & 1;
+}
+
+// bitmaps longer than 32 pages, peeking, dirty bits,
+// and counting pages that aren't mapped.
+void
+pgbits_test()
+{
+  char *buf;
+  static uchar bits[NBITPAGES / 8 + 1];
+  int pages[] = { 0, 33, 500, NBITPAGES - 1 };
+  int npages = sizeof(pages) / sizeof(pages[0]);
+  int i, unmapped;
+
+  printf("pgbits_test starting\n");
+  testname = "pgbits_test";
+  // mmap maps pages as they are touched, and never with megapages,
+  // so only these are mapped.
+  buf = mmap(0, NBITPAGES * PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if (buf == (char *) -1)
+    err("mmap failed");
+  for (i = 0; i < npages; i++)
+    buf[pages[i] * PGSIZE] = 1;
+
+  unmapped = pgaccess(buf, NBITPAGES, bits, PGBITS_PEEK);
+  if (unmapped != NBITPAGES - npages)
+    err("wrong count of unmapped pages");
+  for (i = 0; i < npages; i++)
+    if (!bitset(bits, pages[i]))
+      err("access bit not set");
+  if (pgaccess(buf, NBITPAGES, bits, 0) < 0)
+    err("pgaccess failed");
+  for (i = 0; i < npages; i++)
+    if (!bitset(bits, pages[i]))
+      err("access bit not kept by PGBITS_PEEK");
+  if (pgaccess(buf, NBITPAGES, bits, 0) < 0)
+    err("pgaccess failed");
+  for (i = 0; i < NBITPAGES; i++)
+    if (bitset(bits, i))
+      err("access bit not cleared");
+
+  if (pgdirty(buf, NBITPAGES, bits, 0) < 0)
+    err("pgdirty failed");
+  for (i = 0; i < npages; i++)
+    if (!bitset(bits, pages[i]))
+      err("dirty bit not set");
+  (void) *(volatile char *) &buf[33 * PGSIZE];
+  if (pgdirty(buf, NBITPAGES, bits, 0) < 0)
+    err("pgdirty failed");
+  for (i = 0; i < NBITPAGES; i++)
+    if (bitset(bits, i))
+      err("dirty bit set by a load");
+
+  if (pgaccess(buf, -1, bits, 0) >= 0)
+    err("negative page count");
+  if (pgaccess(buf, 1, bits, 0x100) >= 0)
+    err("unknown flag");
+
+  if (munmap(buf, NBITPAGES * PGSIZE) != 0)
+    err("munmap failed");
+  printf("pgbits_test: OK\n");
+}
@@ -0,0 +1,117 @@
+//
+// tests for page reference counts that do not fit in a byte.
+//
+
+#include "kernel/types.h"
+#include "kernel/riscv.h"
+#include "user/user.h"
+
+// generations of forked processes that share the parent's
+// page copy-on-write, more than a u8 refcount can count.
+#define NFORK 300
+
+int
+check(char *p, int seed)
+{
+  for(int i = 0; i < PGSIZE; i++){
+    if(p[i] != (char) ((i + seed) % 97))
+      return 0;
+  }
+  return 1;
+}
+
+void
+fill(char *p, int seed)
+{
+  for(int i = 0; i < PGSIZE; i++)
+    p[i] = (i + seed) % 97;
+}
+
+// a chain of NFORK forks of one copy-on-write page. each
+// child forks the next generation before writing its own
+// copy of the page and exiting, so the chain never needs
+// more than a few process slots. the last generation
+// reports over a pipe, and the parent then checks its
+// page was never written or freed under it.
+void
+forkchaintest()
+{
+  int fds[2];
+  char c;
+
+  printf("fork chain: ");
+
+  char *brk = sbrk(2 * PGSIZE);
+  if(brk == (char *) -1){
+    printf("sbrk failed\n");
+    exit(1);
+  }
+  char *p = (char *) PGROUNDUP((uint64) brk);
+  fill(p, 0);
+
+  if(pipe(fds) != 0){
+    printf("pipe() failed\n");
+    exit(1);
+  }
+
+  int pid = fork();
+  if(pid < 0){
+    printf("fork() failed\n");
+    exit(1);
+  }
+  if(pid == 0){
+    close(fds[0]);
+    for(int depth = 1; ; depth++){
+      if(!check(p, 0)){
+        printf("error: generation %d read the wrong value\n", depth);
+        write(fds[1], "x", 1);
+        exit(1);
+      }
+      if(depth == NFORK){
+        write(fds[1], "k", 1);
+        exit(0);
+      }
+      // the parent's page is still shared with every
+      // generation that hasn't written its copy yet.
+      pid = fork();
+      if(pid < 0){
+        printf("error: fork() at generation %d failed\n", depth);
+        write(fds[1], "x", 1);
+        exit(1);
+      }
+      if(pid != 0){
+        fill(p, depth);
+        if(!check(p, depth)){
+          printf("error: generation %d lost its write\n", depth);
+          write(fds[1], "x", 1);
+        }
+        exit(0);
+      }
+    }
+  }
+
+  close(fds[1]);
+  if(read(fds[0], &c, 1) != 1 || c != 'k'){
+    printf("error: the fork chain failed\n");
+    exit(1);
+  }
+  close(fds[0]);
+  wait(0);
+
+  if(!check(p, 0)){
+    printf("error: parent read the wrong value\n");
+    exit(1);
+  }
+
+  printf("ok\n");
+}
+
+int
+main(int argc, char *argv[])
+{
+  forkchaintest();
+
+  printf("ALL REFCOUNT TESTS PASSED\n");
+
+  exit(0);
+}
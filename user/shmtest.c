@@ -0,0 +1,231 @@
+//
+// tests for shared memory segments.
+//
+
+#include "kernel/types.h"
+#include "kernel/riscv.h"
+#include "kernel/fcntl.h"
+#include "user/user.h"
+
+#define SHM_FAILED ((char *) 0xffffffffffffffffL)
+
+char *testname = "???";
+
+void
+err(char *why)
+{
+  printf("shmtest: %s failed: %s, pid=%d\n", testname, why, getpid());
+  exit(1);
+}
+
+// a ring buffer in a shared memory segment.
+struct ring {
+  volatile uint head;   // next slot the producer fills
+  volatile uint tail;   // next slot the consumer empties
+  volatile uint slots[(PGSIZE - 2*sizeof(uint)) / sizeof(uint)];
+};
+
+#define NSLOTS (sizeof(((struct ring *) 0)->slots) / sizeof(uint))
+#define NITEMS 20000
+
+void
+produce(int key)
+{
+  struct ring *r = (struct ring *) shmat(key, sizeof(struct ring));
+  if((char *) r == SHM_FAILED)
+    err("producer shmat");
+  for(uint i = 0; i < NITEMS; i++){
+    while(r->head - r->tail == NSLOTS)
+      sleep(1);
+    r->slots[r->head % NSLOTS] = i * 7;
+    __sync_synchronize();
+    r->head++;
+  }
+  if(shmdt(r) != 0)
+    err("producer shmdt");
+  exit(0);
+}
+
+void
+consume(int key)
+{
+  struct ring *r = (struct ring *) shmat(key, sizeof(struct ring));
+  if((char *) r == SHM_FAILED)
+    err("consumer shmat");
+  for(uint i = 0; i < NITEMS; i++){
+    while(r->head == r->tail)
+      sleep(1);
+    __sync_synchronize();
+    if(r->slots[r->tail % NSLOTS] != i * 7)
+      err("wrong item");
+    r->tail++;
+  }
+  if(shmdt(r) != 0)
+    err("consumer shmdt");
+  exit(0);
+}
+
+// a producer and a consumer attach to the same segment
+// by key, and pass items through it without a pipe.
+void
+pctest()
+{
+  testname = "producer/consumer";
+  printf("%s: ", testname);
+
+  // the parent stays attached, so the segment outlives
+  // whichever child detaches first.
+  char *p = shmat(1, sizeof(struct ring));
+  if(p == SHM_FAILED)
+    err("shmat");
+
+  int pids[2];
+  for(int i = 0; i < 2; i++){
+    pids[i] = fork();
+    if(pids[i] < 0)
+      err("fork");
+    if(pids[i] == 0){
+      if(i == 0)
+        produce(1);
+      else
+        consume(1);
+    }
+  }
+  for(int i = 0; i < 2; i++){
+    int xstatus;
+    wait(&xstatus);
+    if(xstatus != 0)
+      exit(1);
+  }
+  if(((struct ring *) p)->tail != NITEMS)
+    err("items lost");
+  if(shmdt(p) != 0)
+    err("shmdt");
+
+  printf("OK\n");
+}
+
+// a forked child keeps writing to the segment's pages,
+// rather than copies of them.
+void
+forktest()
+{
+  testname = "fork";
+  printf("%s: ", testname);
+
+  char *p = shmat(2, 2*PGSIZE);
+  if(p == SHM_FAILED)
+    err("shmat");
+  int pid = fork();
+  if(pid < 0)
+    err("fork");
+  if(pid == 0){
+    p[0] = 'c';
+    p[PGSIZE] = 'd';
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+  if(p[0] != 'c' || p[PGSIZE] != 'd')
+    err("child's writes not seen");
+  if(shmdt(p) != 0)
+    err("shmdt");
+
+  printf("OK\n");
+}
+
+// a segment is freed when the last process detaches,
+// and attaching to its key again makes a fresh one.
+void
+lifetimetest()
+{
+  testname = "lifetime";
+  printf("%s: ", testname);
+
+  char *p = shmat(3, PGSIZE);
+  if(p == SHM_FAILED)
+    err("shmat");
+  if(p[0] != 0)
+    err("segment not zeroed");
+  p[0] = 'x';
+  char *q = shmat(3, PGSIZE);
+  if(q == SHM_FAILED || q == p)
+    err("second shmat");
+  if(q[0] != 'x')
+    err("second mapping not shared");
+  if(shmdt(p) != 0)
+    err("shmdt");
+  if(q[0] != 'x')
+    err("segment freed while still attached");
+  if(shmdt(q) != 0)
+    err("second shmdt");
+  p = shmat(3, PGSIZE);
+  if(p == SHM_FAILED)
+    err("shmat after freeing");
+  if(p[0] != 0)
+    err("old contents after freeing");
+  if(shmdt(p) != 0)
+    err("shmdt after freeing");
+
+  // pages of freed segments go back to the allocator.
+  for(int i = 0; i < 200; i++){
+    p = shmat(4, 128*PGSIZE);
+    if(p == SHM_FAILED)
+      err("segment pages leaked");
+    p[127*PGSIZE] = 'y';
+    if(shmdt(p) != 0)
+      err("shmdt in loop");
+  }
+
+  printf("OK\n");
+}
+
+// bad arguments are rejected.
+void
+argstest()
+{
+  testname = "args";
+  printf("%s: ", testname);
+
+  if(shmat(5, 0) != SHM_FAILED)
+    err("creating an empty segment");
+  if(shmat(5, 1024*PGSIZE) != SHM_FAILED)
+    err("creating a segment too large");
+  char *p = shmat(5, PGSIZE);
+  if(p == SHM_FAILED)
+    err("shmat");
+  if(shmat(5, 2*PGSIZE) != SHM_FAILED)
+    err("attaching with a size larger than the segment");
+  if(shmdt(p + PGSIZE) != -1)
+    err("shmdt of an address with no segment");
+  if(mprotect(p, PGSIZE, PROT_READ) != -1)
+    err("mprotect of a segment");
+  if(shmdt(p) != 0)
+    err("shmdt");
+  if(shmdt(p) != -1)
+    err("shmdt twice");
+  char *m = mmap(0, PGSIZE, PROT_READ | PROT_WRITE, MAP_SHARED | MAP_ANONYMOUS, -1, 0);
+  if(m == SHM_FAILED)
+    err("mmap");
+  if(shmdt(m) != -1)
+    err("shmdt of an mmap region");
+  if(munmap(m, PGSIZE) != 0)
+    err("munmap");
+
+  printf("OK\n");
+}
+
+int
+main(int argc, char *argv[])
+{
+  pctest();
+  forktest();
+  lifetimetest();
+  argstest();
+
+  printf("ALL SHM TESTS PASSED\n");
+
+  exit(0);
+}
@@ -29,10 +29,17 @@ int uptime(void);
 int trace(int);
 int sysinfo(struct sysinfo *);
 int shutdown(void);
-int pgaccess(void *base, int len, void *mask);
+int pgaccess(void *base, int len, void *mask, int flags);
+int pgdirty(void *base, int len, void *mask, int flags);
 int ugetpid(void);
 int sigalarm(int ticks, void (*handler)());
 int sigreturn(void);
+void *mmap(void *addr, uint len, int prot, int flags, int fd, uint off);
+int munmap(void *addr, uint len);
+int mprotect(void *addr, uint len, int prot);
+void *shmat(int key, uint size);
+int shmdt(void *addr);
+int faultstats(int pid, struct faultstats *);
 
 // ulib.c
 int stat(const char*, struct stat*);
@@ -285,6 +285,100 @@ rwsbrk()
   exit(0);
 }
 
+// copying to a user page the kernel can't write faults in the
+// kernel. the fault is recovered from by the exception table, and
+// makes the system call fail rather than panicking the kernel.
+void
+copyfault(char *s)
+{
+  int fd = open("README", O_RDONLY);
+  if(fd < 0){
+    printf("%s: open(README) failed\n", s);
+    exit(1);
+  }
+  char *ro = mmap(0, PGSIZE, PROT_READ, MAP_PRIVATE, fd, 0);
+  if(ro == (char *) 0xffffffffffffffffL){
+    printf("%s: mmap failed\n", s);
+    exit(1);
+  }
+  char *heap = sbrk(PGSIZE);
+  if(heap == (char *) 0xffffffffffffffffL){
+    printf("%s: sbrk failed\n", s);
+    exit(1);
+  }
+  heap[0] = 'x';
+  if(mprotect(heap, PGSIZE, PROT_READ) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+
+  // the mapped page was never touched, so the copy faults on an
+  // unmapped page. the heap page is mapped, but read-only.
+  char *addrs[] = { ro, heap };
+  for(int ai = 0; ai < sizeof(addrs)/sizeof(addrs[0]); ai++){
+    int n = read(fd, addrs[ai], 10);
+    if(n != -1){
+      printf("%s: read(fd, %p, 10) returned %d, not -1\n", s, addrs[ai], n);
+      exit(1);
+    }
+  }
+  if(heap[0] != 'x'){
+    printf("%s: read() changed a read-only page\n", s);
+    exit(1);
+  }
+  close(fd);
+}
+
+// system calls must refuse to copy to or from pages mapped in the
+// process's page table that aren't user memory, rather than faulting
+// in the kernel or writing over the kernel's own pages.
+void
+kernelpages(char *s)
+{
+  char *none = mmap(0, PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+  if(none == (char *) 0xffffffffffffffffL){
+    printf("%s: mmap failed\n", s);
+    exit(1);
+  }
+  none[0] = 'x';
+  if(mprotect(none, PGSIZE, PROT_NONE) != 0){
+    printf("%s: mprotect failed\n", s);
+    exit(1);
+  }
+  uint64 addrs[] = { 0, (uint64) none, USYSCALL, TRAPFRAME, TRAMPOLINE };
+
+  for(int ai = 0; ai < sizeof(addrs)/sizeof(addrs[0]); ai++){
+    uint64 addr = addrs[ai];
+
+    int fd = open("README", O_RDONLY);
+    if(fd < 0){
+      printf("%s: open(README) failed\n", s);
+      exit(1);
+    }
+    int n = read(fd, (void*)addr, 10);
+    if(n >= 0){
+      printf("%s: read(fd, %p, 10) returned %d, not -1\n", s, addr, n);
+      exit(1);
+    }
+    close(fd);
+
+    if(sysinfo((struct sysinfo *) addr) != -1){
+      printf("%s: sysinfo(%p) succeeded\n", s, addr);
+      exit(1);
+    }
+    if(pgaccess(buf, 1, (void *) (addr + 1), 0) != -1){
+      printf("%s: pgaccess(buf, 1, %p) succeeded\n", s, addr + 1);
+      exit(1);
+    }
+  }
+
+  // the PROT_NONE page was left as it was.
+  if(mprotect(none, PGSIZE, PROT_READ) != 0 || none[0] != 'x'){
+    printf("%s: PROT_NONE page changed\n", s);
+    exit(1);
+  }
+}
+
 // test O_TRUNC.
 void
 truncate1(char *s)
@@ -786,6 +880,62 @@ pipe1(char *s)
 }
 
 
+// pages of a mapped file that were never touched are read in before
+// pipewrite() and piperead() take the pipe's lock, rather than by
+// copyin() and copyout(), which would sleep holding it.
+void
+pipemmap(char *s)
+{
+  enum { N=100 };
+  char expected[N];
+  int fds[2];
+
+  int fd = open("README", O_RDONLY);
+  if(fd < 0){
+    printf("%s: open(README) failed\n", s);
+    exit(1);
+  }
+  if(read(fd, expected, N) != N){
+    printf("%s: read(README) failed\n", s);
+    exit(1);
+  }
+  char *src = mmap(0, PGSIZE, PROT_READ, MAP_PRIVATE, fd, 0);
+  char *dst = mmap(0, PGSIZE, PROT_READ | PROT_WRITE, MAP_PRIVATE, fd, 0);
+  close(fd);
+  if(src == (char *) 0xffffffffffffffffL || dst == (char *) 0xffffffffffffffffL){
+    printf("%s: mmap failed\n", s);
+    exit(1);
+  }
+  if(pipe(fds) != 0){
+    printf("%s: pipe() failed\n", s);
+    exit(1);
+  }
+
+  if(write(fds[1], src, N) != N){
+    printf("%s: write from an untouched mapped page failed\n", s);
+    exit(1);
+  }
+  if(read(fds[0], buf, N) != N || memcmp(buf, expected, N) != 0){
+    printf("%s: pipe returned the wrong data\n", s);
+    exit(1);
+  }
+
+  if(write(fds[1], "x", 1) != 1){
+    printf("%s: write failed\n", s);
+    exit(1);
+  }
+  if(read(fds[0], dst + 1, 1) != 1){
+    printf("%s: read into an untouched mapped page failed\n", s);
+    exit(1);
+  }
+  if(dst[0] != expected[0] || dst[1] != 'x'){
+    printf("%s: mapped page has the wrong data\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+}
+
 // test if child is killed (status = -1)
 void
 killstatus(char *s)
@@ -2117,6 +2267,76 @@ sbrkmuch(char *s)
   }
 }
 
+// large, aligned parts of the heap may be backed by 2 MiB
+// megapages. check that they still act like single pages
+// when shared with a child, copied to and from by the
+// kernel, and partly freed by sbrk.
+void
+sbrkmega(char *s)
+{
+  enum { MEGA=2*1024*1024 };
+  char *oldbrk, *a;
+  int pid, xstatus, fds[2];
+
+  oldbrk = sbrk(0);
+  a = (char *) (((uint64) oldbrk + MEGA - 1) & ~(uint64)(MEGA - 1));
+  if(sbrk(a + 2*MEGA - oldbrk) == (char*)0xffffffffffffffffL){
+    printf("%s: sbrk failed\n", s);
+    exit(1);
+  }
+  for(int i = 0; i < 2*MEGA; i += PGSIZE)
+    a[i] = i / PGSIZE;
+
+  pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    for(int i = 0; i < 2*MEGA; i += PGSIZE)
+      if(a[i] != (char) (i / PGSIZE))
+        exit(1);
+    a[PGSIZE] = 'c';
+    exit(0);
+  }
+  wait(&xstatus);
+  if(xstatus != 0){
+    printf("%s: child saw wrong values\n", s);
+    exit(1);
+  }
+  if(a[PGSIZE] != 1){
+    printf("%s: child's write reached the parent\n", s);
+    exit(1);
+  }
+
+  if(pipe(fds) != 0){
+    printf("%s: pipe failed\n", s);
+    exit(1);
+  }
+  if(write(fds[1], a + 3*PGSIZE, 10) != 10 || read(fds[0], a + MEGA + 5*PGSIZE, 10) != 10){
+    printf("%s: pipe copy failed\n", s);
+    exit(1);
+  }
+  close(fds[0]);
+  close(fds[1]);
+  if(a[MEGA + 5*PGSIZE] != 3){
+    printf("%s: wrong value copied\n", s);
+    exit(1);
+  }
+
+  // free the back half of the second megapage.
+  sbrk(-(MEGA/2));
+  for(int i = 0; i < 2*MEGA - MEGA/2; i += PGSIZE){
+    char want = i == MEGA + 5*PGSIZE ? 3 : (char) (i / PGSIZE);
+    if(a[i] != want){
+      printf("%s: sbrk shrink lost a value\n", s);
+      exit(1);
+    }
+  }
+
+  sbrk(-(sbrk(0) - oldbrk));
+}
+
 // can we read the kernel's memory?
 void
 kernmem(char *s)
@@ -2405,10 +2625,13 @@ stacktest(char *s)
   
   pid = fork();
   if(pid == 0) {
-    char *sp = (char *) r_sp();
-    sp -= PGSIZE;
-    // the *sp should cause a trap.
-    printf("%s: stacktest: read below stack %p\n", s, *sp);
+    // the stack grows down on demand, but no further than
+    // MAXSTACK pages, so one of these reads should reach
+    // the guard page below it and cause a trap.
+    char *sp = (char *) PGROUNDDOWN(r_sp());
+    for(int i = 0; i <= MAXSTACK; i++, sp -= PGSIZE)
+      *(volatile char *)sp;
+    printf("%s: stacktest: read below stack %p\n", s, sp);
     exit(1);
   } else if(pid < 0){
     printf("%s: fork failed\n", s);
@@ -2421,6 +2644,43 @@ stacktest(char *s)
     exit(xstatus);
 }
 
+// recurse with a page-sized frame, so the stack has to grow.
+int
+stackdepth(int n)
+{
+  volatile char frame[PGSIZE];
+
+  frame[0] = n;
+  if(n == 0)
+    return 0;
+  return frame[0] + stackdepth(n - 1);
+}
+
+// check that the user stack grows down past its first page,
+// and that a forked child gets a copy of the grown stack.
+void
+stackgrow(char *s)
+{
+  int n = MAXSTACK / 2;
+  int pid;
+  int xstatus;
+
+  if(stackdepth(n) != n*(n+1)/2){
+    printf("%s: wrong sum from deep stack\n", s);
+    exit(1);
+  }
+
+  pid = fork();
+  if(pid == 0) {
+    exit(stackdepth(n) == n*(n+1)/2 ? 0 : 1);
+  } else if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  wait(&xstatus);
+  exit(xstatus);
+}
+
 // check that writes to text segment fault
 void
 textwrite(char *s)
@@ -2581,6 +2841,8 @@ struct test {
   {copyinstr2, "copyinstr2"},
   {copyinstr3, "copyinstr3"},
   {rwsbrk, "rwsbrk" },
+  {kernelpages, "kernelpages"},
+  {copyfault, "copyfault"},
   {truncate1, "truncate1"},
   {truncate2, "truncate2"},
   {truncate3, "truncate3"},
@@ -2594,6 +2856,7 @@ struct test {
   {dirtest, "dirtest"},
   {exectest, "exectest"},
   {pipe1, "pipe1"},
+  {pipemmap, "pipemmap"},
   {killstatus, "killstatus"},
   {preempt, "preempt"},
   {exitwait, "exitwait"},
@@ -2620,6 +2883,7 @@ struct test {
   {forktest, "forktest"},
   {sbrkbasic, "sbrkbasic"},
   {sbrkmuch, "sbrkmuch"},
+  {sbrkmega, "sbrkmega"},
   {kernmem, "kernmem"},
   {MAXVAplus, "MAXVAplus"},
   {sbrkfail, "sbrkfail"},
@@ -2629,6 +2893,7 @@ struct test {
   {bigargtest, "bigargtest"},
   {argptest, "argptest"},
   {stacktest, "stacktest"},
+  {stackgrow, "stackgrow"},
   {textwrite, "textwrite"},
   {pgbug, "pgbug" },
   {sbrkbugs, "sbrkbugs" },
@@ -2924,6 +3189,52 @@ outofinodes(char *s)
   }
 }
 
+// fill more memory than is free, a page at a time so that no
+// megapages are used, so that at least half the swap area is
+// needed, and check that the pages come back intact.
+void
+swapout(char *s)
+{
+  struct sysinfo info;
+
+  if(sysinfo(&info) < 0){
+    printf("%s: sysinfo failed\n", s);
+    exit(1);
+  }
+  // the OOM killer only runs once free pages are below the
+  // minimum watermark and the swap area is full.
+  int n = info.free_pages - info.min_watermark + NSWAP / 2;
+  int pid = fork();
+  if(pid < 0){
+    printf("%s: fork failed\n", s);
+    exit(1);
+  }
+  if(pid == 0){
+    int *start = (int *) sbrk(0);
+    for(int i = 0; i < n; i++){
+      int *page = (int *) sbrk(PGSIZE);
+      if(page == (int *) 0xffffffffffffffffL){
+        printf("%s: sbrk failed\n", s);
+        exit(1);
+      }
+      page[0] = i;
+      page[PGSIZE / sizeof(int) - 1] = ~i;
+    }
+    for(int i = 0; i < n; i++){
+      int *page = (int *) ((char *) start + i * PGSIZE);
+      if(page[0] != i || page[PGSIZE / sizeof(int) - 1] != ~i){
+        printf("%s: page %d has the wrong contents\n", s, i);
+        exit(1);
+      }
+    }
+    exit(0);
+  }
+  int xstatus;
+  wait(&xstatus);
+  if(xstatus != 0)
+    exit(1);
+}
+
 struct test slowtests[] = {
   {bigdir, "bigdir"},
   {manywrites, "manywrites"},
@@ -2931,6 +3242,7 @@ struct test slowtests[] = {
   {execout, "execout"},
   {diskfull, "diskfull"},
   {outofinodes, "outofinodes"},
+  {swapout, "swapout"},
     
   { 0, 0},
 };
@@ -40,5 +40,12 @@ entry("trace");
 entry("sysinfo");
 entry("shutdown");
 entry("pgaccess");
+entry("pgdirty");
 entry("sigalarm");
 entry("sigreturn");
+entry("mmap");
+entry("munmap");
+entry("mprotect");
+entry("shmat");
+entry("shmdt");
+entry("faultstats");